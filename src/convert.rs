@@ -0,0 +1,171 @@
+use crate::error::Error;
+use auxon_sdk::api::{AttrVal, BigInt, Nanoseconds};
+use pyo3::prelude::*;
+use pyo3::types::{
+    PyBool, PyBytes, PyDateTime, PyDelta, PyDeltaAccess, PyDict, PyFloat, PyLong, PyString,
+    PyTimeAccess,
+};
+use std::fmt::Write;
+
+const NANOS_PER_SEC: i128 = 1_000_000_000;
+const NANOS_PER_MICRO: i128 = 1_000;
+const SECS_PER_DAY: i128 = 86_400;
+
+/// Convert a dict of user-provided attributes into attr key/value pairs, prefixing the
+/// keys with `prefix` (e.g. `event.`) when they don't have it already.
+/// `None` values are omitted.
+pub(crate) fn attrs_from_dict(
+    dict: &Bound<'_, PyDict>,
+    prefix: &str,
+) -> PyResult<Vec<(String, AttrVal)>> {
+    let mut attrs = Vec::with_capacity(dict.len());
+    for (k, v) in dict.iter() {
+        let k: String = k.extract()?;
+        if v.is_none() {
+            continue;
+        }
+        let val = attr_val(&k, &v)?;
+        let key = if k.starts_with(prefix) {
+            k
+        } else {
+            format!("{prefix}{k}")
+        };
+        attrs.push((key, val));
+    }
+    Ok(attrs)
+}
+
+/// Convert a Python value into the matching [`AttrVal`] variant.
+///
+/// * `bool` -> `Bool`
+/// * `int` -> `Integer`, or `BigInt` when it doesn't fit in 64 bits
+/// * `float` -> `Float`
+/// * `str` -> `String`
+/// * `bytes` -> hex-encoded `String`
+/// * `datetime` -> `Timestamp`, nanoseconds since the Unix epoch
+/// * `timedelta` -> `Integer` nanoseconds
+pub(crate) fn attr_val(key: &str, value: &Bound<'_, PyAny>) -> PyResult<AttrVal> {
+    // bool is a subclass of int, so it has to be checked first
+    if let Ok(b) = value.downcast::<PyBool>() {
+        Ok(b.is_true().into())
+    } else if value.is_instance_of::<PyLong>() {
+        let i: i128 = value
+            .extract()
+            .map_err(|_| unsupported(key, value, "integer out of 128-bit range"))?;
+        Ok(BigInt::new_attr_val(i))
+    } else if let Ok(f) = value.downcast::<PyFloat>() {
+        Ok(f.value().into())
+    } else if let Ok(s) = value.downcast::<PyString>() {
+        Ok(s.to_str()?.to_owned().into())
+    } else if let Ok(b) = value.downcast::<PyBytes>() {
+        let mut hex = String::with_capacity(b.as_bytes().len() * 2);
+        for byte in b.as_bytes() {
+            write!(hex, "{byte:02x}").expect("write to string");
+        }
+        Ok(hex.into())
+    } else if let Ok(dt) = value.downcast::<PyDateTime>() {
+        // Split off the microseconds so the float seconds from timestamp() don't
+        // lose precision
+        let micros = dt.get_microsecond() as i128;
+        let ts: f64 = dt.call_method0("timestamp")?.extract()?;
+        let secs = (ts - (micros as f64 / 1e6)).round() as i128;
+        let nanos = secs * NANOS_PER_SEC + micros * NANOS_PER_MICRO;
+        let nanos = u64::try_from(nanos)
+            .map_err(|_| unsupported(key, value, "datetime before the Unix epoch"))?;
+        Ok(Nanoseconds::from(nanos).into())
+    } else if let Ok(td) = value.downcast::<PyDelta>() {
+        let secs = td.get_days() as i128 * SECS_PER_DAY + td.get_seconds() as i128;
+        let nanos = secs * NANOS_PER_SEC + td.get_microseconds() as i128 * NANOS_PER_MICRO;
+        Ok(BigInt::new_attr_val(nanos))
+    } else {
+        let type_name = value.get_type().name()?.to_string();
+        Err(unsupported(key, value, &type_name))
+    }
+}
+
+fn unsupported(key: &str, value: &Bound<'_, PyAny>, reason: &str) -> PyErr {
+    let value = value
+        .repr()
+        .map(|r| r.to_string())
+        .unwrap_or_else(|_| "<unknown>".to_owned());
+    Error::UnsupportedAttrValue {
+        key: key.to_owned(),
+        value,
+        reason: reason.to_owned(),
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::IntoPyDict;
+
+    /// Convert the result of evaluating `expr`, with `datetime` imported
+    fn convert(expr: &str) -> PyResult<AttrVal> {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let globals = [("datetime", py.import_bound("datetime")?)].into_py_dict_bound(py);
+            let value = py.eval_bound(expr, Some(&globals), None)?;
+            attr_val("key", &value)
+        })
+    }
+
+    #[test]
+    fn bool_is_not_an_int() {
+        assert_eq!(convert("True").unwrap(), AttrVal::Bool(true));
+        assert_eq!(convert("1").unwrap(), AttrVal::Integer(1));
+    }
+
+    #[test]
+    fn int_past_64_bits_is_a_big_int() {
+        assert_eq!(convert("-(2**63)").unwrap(), AttrVal::Integer(i64::MIN));
+        assert_eq!(convert("2**64").unwrap(), BigInt::new_attr_val(1 << 64));
+        assert!(matches!(convert("2**64").unwrap(), AttrVal::BigInt(_)));
+    }
+
+    #[test]
+    fn int_past_128_bits_is_rejected() {
+        let err = convert("2**128").unwrap_err();
+        Python::with_gil(|py| {
+            let kind: String = err
+                .value_bound(py)
+                .getattr("kind")
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(kind, "UnsupportedAttrValue");
+        });
+    }
+
+    #[test]
+    fn datetime_keeps_microseconds() {
+        let val =
+            convert("datetime.datetime(2024, 1, 2, 3, 4, 5, 123456, tzinfo=datetime.timezone.utc)")
+                .unwrap();
+        assert_eq!(
+            val,
+            AttrVal::Timestamp(Nanoseconds::from(1_704_164_645_123_456_000))
+        );
+    }
+
+    #[test]
+    fn negative_timedelta() {
+        assert_eq!(
+            convert("datetime.timedelta(microseconds=-1)").unwrap(),
+            AttrVal::Integer(-1_000)
+        );
+        assert_eq!(
+            convert("datetime.timedelta(days=-1, seconds=1)").unwrap(),
+            AttrVal::Integer(-86_399_000_000_000)
+        );
+    }
+
+    #[test]
+    fn bytes_are_hex() {
+        assert_eq!(
+            convert("b'\\x00\\xff\\x10'").unwrap(),
+            AttrVal::String("00ff10".into())
+        );
+    }
+}
@@ -1,11 +1,135 @@
+use auxon_sdk::ingest_client::{
+    dynamic::DynamicIngestError, IngestClientInitializationError, IngestError,
+};
+use pyo3::create_exception;
 use pyo3::exceptions::PyOSError;
 use pyo3::prelude::*;
 
+create_exception!(
+    modality_client,
+    ModalityError,
+    PyOSError,
+    "Base class of every error raised by modality_client, an `OSError` like the errors \
+     raised before it. Carries `kind`, `message`, `error_kind`, `suite_name` and \
+     `test_name` attributes."
+);
+create_exception!(
+    modality_client,
+    NoSuiteActiveError,
+    ModalityError,
+    "A suite or test call was made without a suite being set up."
+);
+create_exception!(
+    modality_client,
+    NoTestActiveError,
+    ModalityError,
+    "A test call was made without a test being set up."
+);
+create_exception!(
+    modality_client,
+    ConfigurationError,
+    ModalityError,
+    "An invalid argument or modality configuration."
+);
+create_exception!(
+    modality_client,
+    AuthError,
+    ModalityError,
+    "The auth token couldn't be loaded, or modalityd rejected it."
+);
+create_exception!(
+    modality_client,
+    IngestConnectionError,
+    ModalityError,
+    "modalityd couldn't be reached, or the connection to it failed."
+);
+create_exception!(
+    modality_client,
+    IngestProtocolError,
+    ModalityError,
+    "modalityd and the client disagreed about the ingest protocol."
+);
+
+/// Register the exception classes on the module
+pub(crate) fn add_exceptions(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add("ModalityError", py.get_type_bound::<ModalityError>())?;
+    m.add(
+        "NoSuiteActiveError",
+        py.get_type_bound::<NoSuiteActiveError>(),
+    )?;
+    m.add(
+        "NoTestActiveError",
+        py.get_type_bound::<NoTestActiveError>(),
+    )?;
+    m.add(
+        "ConfigurationError",
+        py.get_type_bound::<ConfigurationError>(),
+    )?;
+    m.add("AuthError", py.get_type_bound::<AuthError>())?;
+    m.add(
+        "IngestConnectionError",
+        py.get_type_bound::<IngestConnectionError>(),
+    )?;
+    m.add(
+        "IngestProtocolError",
+        py.get_type_bound::<IngestProtocolError>(),
+    )?;
+    Ok(())
+}
+
+/// What the client was doing when an error happened
+#[derive(Debug, Default)]
+pub(crate) struct ErrorContext {
+    pub(crate) suite_name: Option<String>,
+    pub(crate) test_name: Option<String>,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("No test suite is active, check the call to 'On Suite Setup'")]
     NoSuiteActive,
 
+    #[error("No test is active, check the call to 'On Test Setup'")]
+    NoTestActive,
+
+    #[error("No running component was started with nonce {0}")]
+    UnknownComponent(u32),
+
+    #[error("Unsupported value {value} for attribute '{key}' ({reason})")]
+    UnsupportedAttrValue {
+        key: String,
+        value: String,
+        reason: String,
+    },
+
+    #[error("Invalid timeline attribute scope '{0}', expected one of 'test', 'suite' or 'run'")]
+    InvalidScope(String),
+
+    #[error("Invalid timeline id '{0}', expected a UUID")]
+    InvalidTimelineId(String),
+
+    #[error("Invalid Robot Framework status '{0}'")]
+    InvalidStatus(String),
+
+    #[error("Invalid ingest URL '{url}' ({reason})")]
+    InvalidIngestUrl { url: String, reason: String },
+
+    #[error("Invalid timeout of {0} seconds, expected a positive number")]
+    InvalidTimeout(f64),
+
+    #[error("Invalid reconnect backoff of {0} seconds, expected zero or a positive number")]
+    InvalidReconnectBackoff(f64),
+
+    #[error("The timeline attribute '{0}' is set by the client and can't be overridden")]
+    ReservedTimelineAttr(String),
+
+    #[error("Invalid listener argument '{arg}' ({reason})")]
+    InvalidListenerArg { arg: String, reason: String },
+
+    #[error("Failed to load the modality configuration. {0}")]
+    ConfigLoad(#[from] auxon_sdk::reflector_config::resolve::ExpandedConfigLoadError),
+
     #[error(transparent)]
     AttrKeyVal(#[from] auxon_sdk::reflector_config::AttrKeyValuePairParseError),
 
@@ -24,6 +148,32 @@ pub enum Error {
     #[error(transparent)]
     AuthLoad(#[from] auxon_sdk::auth_token::LoadAuthTokenError),
 
+    #[error("The ingest queue size must be at least 1")]
+    InvalidQueueSize,
+
+    #[error("The ingest worker has stopped")]
+    IngestWorkerStopped,
+
+    #[error("Timed out after {0:?} waiting for the ingest worker to flush")]
+    FlushTimeout(std::time::Duration),
+
+    #[error(
+        "Not connected to modalityd, holding off on reconnecting after the last attempt failed"
+    )]
+    NotConnected,
+
+    #[error("Timed out waiting for room in the ingest queue")]
+    QueueTimeout,
+
+    #[error("The client has been closed")]
+    ClientClosed,
+
+    #[error("Failed to encode a spool record. {0}")]
+    SpoolEncode(#[from] minicbor::encode::Error<std::io::Error>),
+
+    #[error("Failed to decode a spool record, the spool file may be corrupt. {0}")]
+    SpoolDecode(#[from] minicbor::decode::Error),
+
     #[error(
         "Encountered and IO error while reading the input stream ({})",
         .0.kind()
@@ -31,8 +181,187 @@ pub enum Error {
     Io(#[from] std::io::Error),
 }
 
+impl Error {
+    /// Whether this is a problem talking to modalityd, as opposed to a mistake in how
+    /// the client is used
+    pub fn is_ingest_error(&self) -> bool {
+        matches!(
+            self,
+            Error::ConfigLoad(_)
+                | Error::IngestClientInitialization(_)
+                | Error::Ingest(_)
+                | Error::DynamicIngest(_)
+                | Error::AuthDes(_)
+                | Error::AuthLoad(_)
+                | Error::IngestWorkerStopped
+                | Error::FlushTimeout(_)
+                | Error::QueueTimeout
+                | Error::NotConnected
+                | Error::SpoolEncode(_)
+                | Error::SpoolDecode(_)
+                | Error::Io(_)
+        )
+    }
+
+    /// The name of the variant, e.g. `NoSuiteActive`
+    fn kind(&self) -> &'static str {
+        match self {
+            Error::NoSuiteActive => "NoSuiteActive",
+            Error::NoTestActive => "NoTestActive",
+            Error::UnknownComponent(_) => "UnknownComponent",
+            Error::UnsupportedAttrValue { .. } => "UnsupportedAttrValue",
+            Error::InvalidScope(_) => "InvalidScope",
+            Error::InvalidTimelineId(_) => "InvalidTimelineId",
+            Error::InvalidStatus(_) => "InvalidStatus",
+            Error::InvalidIngestUrl { .. } => "InvalidIngestUrl",
+            Error::InvalidTimeout(_) => "InvalidTimeout",
+            Error::InvalidReconnectBackoff(_) => "InvalidReconnectBackoff",
+            Error::ReservedTimelineAttr(_) => "ReservedTimelineAttr",
+            Error::InvalidListenerArg { .. } => "InvalidListenerArg",
+            Error::ConfigLoad(_) => "ConfigLoad",
+            Error::AttrKeyVal(_) => "AttrKeyVal",
+            Error::IngestClientInitialization(_) => "IngestClientInitialization",
+            Error::Ingest(_) => "Ingest",
+            Error::DynamicIngest(_) => "DynamicIngest",
+            Error::AuthDes(_) => "AuthDes",
+            Error::AuthLoad(_) => "AuthLoad",
+            Error::InvalidQueueSize => "InvalidQueueSize",
+            Error::IngestWorkerStopped => "IngestWorkerStopped",
+            Error::FlushTimeout(_) => "FlushTimeout",
+            Error::QueueTimeout => "QueueTimeout",
+            Error::NotConnected => "NotConnected",
+            Error::ClientClosed => "ClientClosed",
+            Error::SpoolEncode(_) => "SpoolEncode",
+            Error::SpoolDecode(_) => "SpoolDecode",
+            Error::Io(_) => "Io",
+        }
+    }
+
+    /// The kind of the underlying error, e.g. `ConnectionRefused` or `Timeout`
+    fn error_kind(&self) -> Option<String> {
+        match self {
+            Error::Io(e) => Some(format!("{:?}", e.kind())),
+            Error::FlushTimeout(_) | Error::QueueTimeout => Some("Timeout".to_owned()),
+            Error::IngestClientInitialization(e) => Some(init_error_kind(e)),
+            Error::Ingest(e) => Some(ingest_error_kind(e)),
+            Error::DynamicIngest(DynamicIngestError::IngestError(e)) => Some(ingest_error_kind(e)),
+            Error::DynamicIngest(DynamicIngestError::NoBoundTimeline) => {
+                Some("NoBoundTimeline".to_owned())
+            }
+            _ => None,
+        }
+    }
+
+    /// Convert to the matching Python exception, with the error's details and `ctx`
+    /// set as attributes
+    pub(crate) fn into_py_err(self, py: Python<'_>, ctx: ErrorContext) -> PyErr {
+        let message = self.to_string();
+        let err = match &self {
+            Error::NoSuiteActive => NoSuiteActiveError::new_err(message.clone()),
+            Error::NoTestActive => NoTestActiveError::new_err(message.clone()),
+            Error::InvalidIngestUrl { .. }
+            | Error::InvalidTimeout(_)
+            | Error::InvalidReconnectBackoff(_)
+            | Error::ReservedTimelineAttr(_)
+            | Error::InvalidListenerArg { .. }
+            | Error::InvalidQueueSize
+            | Error::ConfigLoad(_)
+            | Error::AttrKeyVal(_) => ConfigurationError::new_err(message.clone()),
+            Error::AuthDes(_) | Error::AuthLoad(_) => AuthError::new_err(message.clone()),
+            Error::IngestClientInitialization(_)
+            | Error::IngestWorkerStopped
+            | Error::FlushTimeout(_)
+            | Error::QueueTimeout
+            | Error::NotConnected => IngestConnectionError::new_err(message.clone()),
+            Error::Ingest(e) | Error::DynamicIngest(DynamicIngestError::IngestError(e)) => {
+                ingest_exception(e, message.clone())
+            }
+            Error::DynamicIngest(DynamicIngestError::NoBoundTimeline) => {
+                IngestProtocolError::new_err(message.clone())
+            }
+            _ => ModalityError::new_err(message.clone()),
+        };
+
+        let value = err.value_bound(py);
+        let set = |name: &str, v: PyObject| {
+            if let Err(e) = value.setattr(name, v) {
+                tracing::debug!(error = %e, name, "Failed to set an exception attribute");
+            }
+        };
+        set("kind", self.kind().into_py(py));
+        set("message", message.into_py(py));
+        set("error_kind", self.error_kind().into_py(py));
+        set("suite_name", ctx.suite_name.into_py(py));
+        set("test_name", ctx.test_name.into_py(py));
+        match self {
+            Error::UnknownComponent(nonce) => set("nonce", nonce.into_py(py)),
+            Error::UnsupportedAttrValue { key, value, reason } => {
+                set("key", key.into_py(py));
+                set("value", value.into_py(py));
+                set("reason", reason.into_py(py));
+            }
+            Error::InvalidScope(value)
+            | Error::InvalidTimelineId(value)
+            | Error::InvalidStatus(value)
+            | Error::ReservedTimelineAttr(value) => set("value", value.into_py(py)),
+            Error::InvalidIngestUrl { url: value, reason }
+            | Error::InvalidListenerArg { arg: value, reason } => {
+                set("value", value.into_py(py));
+                set("reason", reason.into_py(py));
+            }
+            Error::InvalidTimeout(value) | Error::InvalidReconnectBackoff(value) => {
+                set("value", value.into_py(py))
+            }
+            _ => (),
+        }
+        err
+    }
+}
+
+fn ingest_exception(err: &IngestError, message: String) -> PyErr {
+    match err {
+        IngestError::LoadConfigError(_) => ConfigurationError::new_err(message),
+        IngestError::AuthenticationError { .. } => AuthError::new_err(message),
+        IngestError::ProtocolError(_)
+        | IngestError::CborEncode(_)
+        | IngestError::CborDecode(_)
+        | IngestError::AttrKeyNaming => IngestProtocolError::new_err(message),
+        IngestError::Timeout(_)
+        | IngestError::IngestClientInitializationError(_)
+        | IngestError::Io(_) => IngestConnectionError::new_err(message),
+    }
+}
+
+fn ingest_error_kind(err: &IngestError) -> String {
+    match err {
+        IngestError::LoadConfigError(_) => "LoadConfigError".to_owned(),
+        IngestError::AuthenticationError { .. } => "AuthenticationError".to_owned(),
+        IngestError::ProtocolError(_) => "ProtocolError".to_owned(),
+        IngestError::CborEncode(_) => "CborEncode".to_owned(),
+        IngestError::CborDecode(_) => "CborDecode".to_owned(),
+        IngestError::Timeout(_) => "Timeout".to_owned(),
+        IngestError::AttrKeyNaming => "AttrKeyNaming".to_owned(),
+        IngestError::IngestClientInitializationError(e) => init_error_kind(e),
+        IngestError::Io(e) => format!("{:?}", e.kind()),
+    }
+}
+
+fn init_error_kind(err: &IngestClientInitializationError) -> String {
+    match err {
+        IngestClientInitializationError::SocketInit(e)
+        | IngestClientInitializationError::SocketConnection { error: e, .. }
+        | IngestClientInitializationError::Io(e) => format!("{:?}", e.kind()),
+        IngestClientInitializationError::NoIps => "NoIps".to_owned(),
+        IngestClientInitializationError::InvalidDnsName(_) => "InvalidDnsName".to_owned(),
+        IngestClientInitializationError::ClientLocalAddrParse(_) => {
+            "ClientLocalAddrParse".to_owned()
+        }
+        IngestClientInitializationError::ParseIngestEndpoint(_) => "ParseIngestEndpoint".to_owned(),
+    }
+}
+
 impl From<Error> for PyErr {
     fn from(err: Error) -> PyErr {
-        PyOSError::new_err(err.to_string())
+        Python::with_gil(|py| err.into_py_err(py, ErrorContext::default()))
     }
 }
@@ -0,0 +1,1046 @@
+use crate::error::Error;
+use crate::spool::{event_ordering, SpoolReader, SpoolRecord, SpoolWriter};
+use auxon_sdk::{
+    api::{AttrVal, TimelineId},
+    auth_token::{decode_auth_token_hex, AuthToken},
+    ingest_client::{
+        dynamic::DynamicIngestClient, IngestClient, IngestError, MODALITY_INGEST_TLS_URL_SCHEME,
+        MODALITY_INGEST_URL_SCHEME,
+    },
+    ingest_protocol::InternedAttrKey,
+    reflector_config::resolve::{resolve_reflector_auth_token, ConfigContext},
+};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tokio::runtime;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, warn};
+use url::Url;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_INGEST_URL: &str = "modality-ingest://127.0.0.1";
+pub(crate) const DEFAULT_RECONNECT_ATTEMPTS: u32 = 3;
+pub(crate) const DEFAULT_RECONNECT_BACKOFF_SECS: f64 = 0.5;
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+/// How long to hold off on reconnecting after the last attempt gave up, so an outage
+/// doesn't stall every flush with another round of attempts
+const RECONNECT_COOLDOWN: Duration = Duration::from_secs(30);
+/// How many records to hold on to for re-sending before flushing on our own
+const MAX_UNFLUSHED: usize = 4096;
+/// How often to check on the worker while waiting on it with a deadline
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How to reconnect after the connection to modalityd fails
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct ReconnectPolicy {
+    /// Reconnect attempts before giving up, 0 to not reconnect at all
+    attempts: u32,
+    /// The delay before the first attempt, doubling for each one after that
+    backoff: Duration,
+}
+
+/// How to reach modalityd. Anything left unset comes from the standard modality
+/// configuration and environment, i.e. the reflector config and `MODALITY_AUTH_TOKEN`
+/// or a reflector auth token file.
+#[derive(Clone, Debug)]
+pub(crate) struct ConnectionConfig {
+    ingest_url: Option<Url>,
+    auth_token: Option<AuthToken>,
+    allow_insecure_tls: Option<bool>,
+    timeout: Duration,
+    reconnect: ReconnectPolicy,
+}
+
+impl ConnectionConfig {
+    pub(crate) fn new(
+        ingest_url: Option<&str>,
+        auth_token: Option<&str>,
+        allow_insecure_tls: Option<bool>,
+        timeout_secs: Option<f64>,
+        reconnect_attempts: u32,
+        reconnect_backoff_secs: f64,
+    ) -> Result<Self, Error> {
+        let ingest_url = ingest_url.map(parse_ingest_url).transpose()?;
+        let auth_token = auth_token.map(decode_auth_token_hex).transpose()?;
+        let timeout = match timeout_secs {
+            Some(secs) => Duration::try_from_secs_f64(secs)
+                .ok()
+                .filter(|t| !t.is_zero())
+                .ok_or(Error::InvalidTimeout(secs))?,
+            None => DEFAULT_TIMEOUT,
+        };
+        let backoff = Duration::try_from_secs_f64(reconnect_backoff_secs)
+            .map_err(|_| Error::InvalidReconnectBackoff(reconnect_backoff_secs))?;
+        Ok(Self {
+            ingest_url,
+            auth_token,
+            allow_insecure_tls,
+            timeout,
+            reconnect: ReconnectPolicy {
+                attempts: reconnect_attempts,
+                backoff,
+            },
+        })
+    }
+
+    async fn connect(&self) -> Result<DynamicIngestClient, Error> {
+        let ConfigContext {
+            config,
+            config_file_parent_dir,
+            ..
+        } = ConfigContext::load_default(None)?;
+        let ingest = config.ingest;
+        let ingest_url = self
+            .ingest_url
+            .clone()
+            .or_else(|| ingest.as_ref().and_then(|i| i.protocol_parent_url.clone()))
+            .unwrap_or_else(|| Url::parse(DEFAULT_INGEST_URL).expect("default ingest url"));
+        let allow_insecure_tls = self
+            .allow_insecure_tls
+            .or_else(|| ingest.as_ref().map(|i| i.allow_insecure_tls))
+            .unwrap_or(false);
+        let auth_token = match self.auth_token.as_ref() {
+            Some(token) => token.clone(),
+            // Looked up the same way as the standard config does, which also checks for
+            // a reflector auth token file
+            None => resolve_reflector_auth_token(None, &config_file_parent_dir)
+                .map_err(IngestError::LoadConfigError)?,
+        };
+        debug!(%ingest_url, allow_insecure_tls, "Connecting to modalityd");
+
+        let client =
+            IngestClient::connect_with_timeout(&ingest_url, allow_insecure_tls, self.timeout)
+                .await?
+                .authenticate(auth_token.into())
+                .await?;
+        Ok(client.into())
+    }
+}
+
+fn parse_ingest_url(s: &str) -> Result<Url, Error> {
+    let invalid = |reason: &str| Error::InvalidIngestUrl {
+        url: s.to_owned(),
+        reason: reason.to_owned(),
+    };
+    let url = Url::parse(s.trim()).map_err(|e| invalid(&e.to_string()))?;
+    if url.scheme() != MODALITY_INGEST_URL_SCHEME && url.scheme() != MODALITY_INGEST_TLS_URL_SCHEME
+    {
+        return Err(invalid(&format!(
+            "expected a '{MODALITY_INGEST_URL_SCHEME}' or '{MODALITY_INGEST_TLS_URL_SCHEME}' URL"
+        )));
+    }
+    if url.host().is_none() {
+        return Err(invalid("missing host"));
+    }
+    Ok(url)
+}
+
+/// A request for the ingest worker thread
+enum Command {
+    OpenTimeline(TimelineId),
+    CloseTimeline,
+    TimelineMetadata(Vec<(String, AttrVal)>),
+    Event {
+        ordering: u128,
+        attrs: Vec<(String, AttrVal)>,
+    },
+    Flush(SyncSender<Result<(), Error>>),
+}
+
+/// Handle to a dedicated thread that owns the tokio runtime and the ingest connection.
+///
+/// Calls only enqueue work on a bounded channel, so they return as soon as there's room
+/// in the queue rather than waiting on the network. Errors encountered by the worker are
+/// reported by the next flush.
+///
+/// With a spool path, the worker writes everything to that file instead when modalityd
+/// can't be reached at startup or the connection fails later on, to be uploaded
+/// afterwards with [`replay_spool`].
+pub(crate) struct IngestWorker {
+    tx: Option<mpsc::Sender<Command>>,
+    thread: Option<JoinHandle<()>>,
+    /// When set, sends give up at this point rather than waiting on a full queue
+    deadline: Option<Instant>,
+    /// Worker errors that a flush couldn't report because it had one already
+    dropped_errors: Arc<AtomicU64>,
+}
+
+impl IngestWorker {
+    /// Start the worker thread and connect to modalityd, waiting for the connection
+    /// to be established
+    pub(crate) fn spawn(
+        queue_size: usize,
+        config: ConnectionConfig,
+        spool_path: Option<PathBuf>,
+        fail_open: bool,
+    ) -> Result<Self, Error> {
+        if queue_size == 0 {
+            return Err(Error::InvalidQueueSize);
+        }
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let (tx, rx) = mpsc::channel(queue_size);
+        let (ready_tx, ready_rx) = oneshot::channel::<Result<(), Error>>();
+        let dropped_errors = Arc::new(AtomicU64::new(0));
+        let worker_dropped_errors = Arc::clone(&dropped_errors);
+
+        let thread = thread::Builder::new()
+            .name("modality-ingest".to_owned())
+            .spawn(move || {
+                rt.block_on(async move {
+                    let mut error = None;
+                    let client = match config.connect().await {
+                        Ok(c) => Some(c),
+                        Err(e) if spool_path.is_some() => {
+                            warn!(error = %e, "Failed to connect to modalityd, spooling to disk");
+                            None
+                        }
+                        Err(e) if fail_open => {
+                            // Held back records are sent if a later flush manages to connect
+                            error!(error = %e, "Failed to connect to modalityd");
+                            error = Some(e);
+                            None
+                        }
+                        Err(e) => {
+                            let _ = ready_tx.send(Err(e));
+                            return;
+                        }
+                    };
+                    let _ = ready_tx.send(Ok(()));
+                    let reconnect = config.reconnect;
+                    let mut worker = Worker::new(client, config, reconnect, spool_path);
+                    if error.is_some() {
+                        worker.retry_after = Some(Instant::now() + RECONNECT_COOLDOWN);
+                    }
+                    worker.error = error;
+                    worker.dropped_errors = worker_dropped_errors;
+                    worker.run(rx).await;
+                })
+            })?;
+
+        ready_rx
+            .blocking_recv()
+            .map_err(|_| Error::IngestWorkerStopped)??;
+        Ok(Self {
+            tx: Some(tx),
+            thread: Some(thread),
+            deadline: None,
+            dropped_errors,
+        })
+    }
+
+    /// The number of worker errors since the last call that a flush didn't report,
+    /// because it was already reporting an earlier one
+    pub(crate) fn take_dropped_errors(&self) -> u64 {
+        self.dropped_errors.swap(0, Ordering::Relaxed)
+    }
+
+    /// Bound how long sends wait for room in the queue, or `None` to wait as long as
+    /// it takes
+    pub(crate) fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    pub(crate) fn open_timeline(&self, timeline_id: TimelineId) -> Result<(), Error> {
+        self.send(Command::OpenTimeline(timeline_id))
+    }
+
+    pub(crate) fn close_timeline(&self) -> Result<(), Error> {
+        self.send(Command::CloseTimeline)
+    }
+
+    pub(crate) fn timeline_metadata(&self, attrs: Vec<(String, AttrVal)>) -> Result<(), Error> {
+        self.send(Command::TimelineMetadata(attrs))
+    }
+
+    pub(crate) fn event(&self, ordering: u128, attrs: Vec<(String, AttrVal)>) -> Result<(), Error> {
+        self.send(Command::Event { ordering, attrs })
+    }
+
+    /// Block until everything queued so far has been sent and flushed, giving up once
+    /// `timeout` has passed
+    pub(crate) fn flush_timeout(&self, timeout: Duration) -> Result<(), Error> {
+        self.start_flush(Some(timeout))?.wait()
+    }
+
+    /// Queue a flush of everything queued so far, to be waited on with
+    /// [`PendingFlush::wait`], giving up once `timeout` has passed if there is one
+    pub(crate) fn start_flush(&self, timeout: Option<Duration>) -> Result<PendingFlush, Error> {
+        let (done_tx, done) = sync_channel(1);
+        let deadline = timeout.map(|t| Instant::now() + t);
+        match deadline {
+            Some(deadline) => {
+                let tx = self.tx.as_ref().ok_or(Error::IngestWorkerStopped)?;
+                // The queue may be full while the worker is busy reconnecting
+                send_by(tx, Command::Flush(done_tx), deadline)?;
+            }
+            None => self.send(Command::Flush(done_tx))?,
+        }
+        Ok(PendingFlush {
+            done,
+            timeout: timeout.zip(deadline),
+        })
+    }
+
+    /// Stop the worker once it has drained the queue, abandoning it if that takes
+    /// longer than `timeout`. Anything sent afterwards fails with
+    /// [`Error::IngestWorkerStopped`].
+    pub(crate) fn shutdown(&mut self, timeout: Duration) {
+        self.tx.take();
+        let Some(thread) = self.thread.take() else {
+            return;
+        };
+        let deadline = Instant::now() + timeout;
+        while !thread.is_finished() {
+            if Instant::now() >= deadline {
+                warn!("The ingest worker didn't stop in time, abandoning it");
+                return;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        let _ = thread.join();
+    }
+
+    fn send(&self, cmd: Command) -> Result<(), Error> {
+        let tx = self.tx.as_ref().ok_or(Error::IngestWorkerStopped)?;
+        match self.deadline {
+            Some(deadline) => send_by(tx, cmd, deadline),
+            None => tx
+                .blocking_send(cmd)
+                .map_err(|_| Error::IngestWorkerStopped),
+        }
+    }
+}
+
+/// A flush queued on the ingest worker, which can be waited on without holding on
+/// to the worker
+pub(crate) struct PendingFlush {
+    done: Receiver<Result<(), Error>>,
+    /// The flush's timeout and when it runs out
+    timeout: Option<(Duration, Instant)>,
+}
+
+impl PendingFlush {
+    /// Block until the worker has flushed, returning the first error it ran into since
+    /// the last flush
+    pub(crate) fn wait(self) -> Result<(), Error> {
+        let Some((timeout, deadline)) = self.timeout else {
+            return self.done.recv().map_err(|_| Error::IngestWorkerStopped)?;
+        };
+        self.done
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .map_err(|e| match e {
+                RecvTimeoutError::Timeout => Error::FlushTimeout(timeout),
+                RecvTimeoutError::Disconnected => Error::IngestWorkerStopped,
+            })?
+    }
+}
+
+/// Queue `cmd`, waiting for room in the queue until `deadline` at the latest
+fn send_by(tx: &mpsc::Sender<Command>, mut cmd: Command, deadline: Instant) -> Result<(), Error> {
+    loop {
+        match tx.try_send(cmd) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Full(c)) if Instant::now() < deadline => {
+                cmd = c;
+                thread::sleep(POLL_INTERVAL);
+            }
+            Err(TrySendError::Full(_)) => return Err(Error::QueueTimeout),
+            Err(TrySendError::Closed(_)) => return Err(Error::IngestWorkerStopped),
+        }
+    }
+}
+
+impl Drop for IngestWorker {
+    fn drop(&mut self) {
+        // Closing the channel lets the worker drain the queue and exit
+        self.tx.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Upload the contents of a spool file to modalityd, returning the number of events sent
+pub(crate) fn replay_spool(path: &Path, config: &ConnectionConfig) -> Result<u64, Error> {
+    let mut reader = SpoolReader::open(path)?;
+    let rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(async {
+        let client = config.connect().await?;
+        let mut worker = Worker::new(Some(client), config.clone(), config.reconnect, None);
+        let mut events = 0;
+        while let Some(record) = reader.next_record()? {
+            if matches!(record, SpoolRecord::Event { .. }) {
+                events += 1;
+            }
+            worker.handle_record(record).await?;
+        }
+        worker.flush().await?;
+        debug!(path = %path.display(), events, "Replayed spool file");
+        Ok(events)
+    })
+}
+
+/// The parts of an ingest client the worker uses, so the tests can stand in for modalityd
+trait IngestConnection {
+    async fn open_timeline(&mut self, id: TimelineId) -> Result<(), Error>;
+    fn close_timeline(&mut self);
+    async fn declare_attr_key(&mut self, key: String) -> Result<InternedAttrKey, Error>;
+    async fn timeline_metadata(
+        &mut self,
+        attrs: HashMap<InternedAttrKey, AttrVal>,
+    ) -> Result<(), Error>;
+    async fn event(
+        &mut self,
+        ordering: u128,
+        attrs: HashMap<InternedAttrKey, AttrVal>,
+    ) -> Result<(), Error>;
+    async fn flush(&mut self) -> Result<(), Error>;
+}
+
+impl IngestConnection for DynamicIngestClient {
+    async fn open_timeline(&mut self, id: TimelineId) -> Result<(), Error> {
+        Ok(DynamicIngestClient::open_timeline(self, id).await?)
+    }
+
+    fn close_timeline(&mut self) {
+        DynamicIngestClient::close_timeline(self)
+    }
+
+    async fn declare_attr_key(&mut self, key: String) -> Result<InternedAttrKey, Error> {
+        Ok(DynamicIngestClient::declare_attr_key(self, key).await?)
+    }
+
+    async fn timeline_metadata(
+        &mut self,
+        attrs: HashMap<InternedAttrKey, AttrVal>,
+    ) -> Result<(), Error> {
+        Ok(DynamicIngestClient::timeline_metadata(self, attrs).await?)
+    }
+
+    async fn event(
+        &mut self,
+        ordering: u128,
+        attrs: HashMap<InternedAttrKey, AttrVal>,
+    ) -> Result<(), Error> {
+        Ok(DynamicIngestClient::event(self, ordering, attrs).await?)
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        Ok(DynamicIngestClient::flush(self).await?)
+    }
+}
+
+/// Opens connections to modalityd for the worker, initially and when reconnecting
+trait Connector {
+    type Connection: IngestConnection;
+
+    async fn connect(&self) -> Result<Self::Connection, Error>;
+}
+
+impl Connector for ConnectionConfig {
+    type Connection = DynamicIngestClient;
+
+    async fn connect(&self) -> Result<DynamicIngestClient, Error> {
+        ConnectionConfig::connect(self).await
+    }
+}
+
+/// The worker thread's side of the channel
+struct Worker<C: Connector = ConnectionConfig> {
+    /// The modalityd connection, `None` while disconnected or spooling
+    client: Option<C::Connection>,
+    connection: C,
+    reconnect: ReconnectPolicy,
+    /// Attr keys declared on the current connection
+    attrs: HashMap<String, InternedAttrKey>,
+    spool_path: Option<PathBuf>,
+    /// Opened the first time something has to be spooled, after which everything else
+    /// goes to the spool too
+    spool: Option<SpoolWriter>,
+    /// The currently open timeline
+    timeline: Option<TimelineId>,
+    /// Everything sent since the last successful flush, starting with the timeline that
+    /// was open at the time, to be re-sent after reconnecting
+    unflushed: Vec<SpoolRecord>,
+    /// The first error since the last flush
+    error: Option<Error>,
+    /// Counts the errors after the first one since the last flush
+    dropped_errors: Arc<AtomicU64>,
+    /// No reconnecting before this, after the last attempt gave up
+    retry_after: Option<Instant>,
+}
+
+impl<C: Connector> Worker<C> {
+    fn new(
+        client: Option<C::Connection>,
+        connection: C,
+        reconnect: ReconnectPolicy,
+        spool_path: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            client,
+            connection,
+            reconnect,
+            attrs: Default::default(),
+            spool_path,
+            spool: None,
+            timeline: None,
+            unflushed: Vec::new(),
+            error: None,
+            dropped_errors: Default::default(),
+            retry_after: None,
+        }
+    }
+
+    async fn run(mut self, mut rx: mpsc::Receiver<Command>) {
+        while let Some(cmd) = rx.recv().await {
+            match cmd {
+                Command::Flush(done) => {
+                    let res = self.flush().await;
+                    let res = match self.error.take() {
+                        Some(e) => {
+                            if res.is_err() {
+                                self.dropped_errors.fetch_add(1, Ordering::Relaxed);
+                            }
+                            Err(e)
+                        }
+                        None => res,
+                    };
+                    let _ = done.send(res);
+                }
+                cmd => {
+                    if let Err(e) = self.handle(cmd).await {
+                        error!(error = %e, "Ingest error");
+                        if self.error.is_some() {
+                            self.dropped_errors.fetch_add(1, Ordering::Relaxed);
+                        } else {
+                            self.error = Some(e);
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Err(e) = self.flush().await {
+            error!(error = %e, "Failed to flush the ingest client on shutdown");
+        }
+        debug!("Ingest worker stopped");
+    }
+
+    async fn handle(&mut self, cmd: Command) -> Result<(), Error> {
+        let record = match cmd {
+            Command::OpenTimeline(id) => SpoolRecord::OpenTimeline { id },
+            Command::CloseTimeline => {
+                self.timeline = None;
+                if let Some(client) = self.client.as_mut() {
+                    client.close_timeline();
+                }
+                // Replay opens each timeline before using it, so there's nothing to record
+                return Ok(());
+            }
+            Command::TimelineMetadata(attrs) => SpoolRecord::TimelineMetadata { attrs },
+            Command::Event { ordering, attrs } => SpoolRecord::event(ordering, attrs),
+            Command::Flush(_) => unreachable!("flush is handled by the run loop"),
+        };
+        self.handle_record(record).await
+    }
+
+    async fn handle_record(&mut self, record: SpoolRecord) -> Result<(), Error> {
+        if let SpoolRecord::OpenTimeline { id } = record {
+            self.timeline = Some(id);
+        }
+        if self.spool.is_some() || (self.client.is_none() && self.spool_path.is_some()) {
+            return self.spool(&[record]);
+        }
+
+        let res = match self.client {
+            Some(_) => self.send(&record).await,
+            // Held back until the next flush reconnects
+            None => Ok(()),
+        };
+        self.unflushed.push(record);
+        if let Err(e) = res {
+            return self.recover(e).await;
+        }
+        if self.unflushed.len() >= MAX_UNFLUSHED {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Send `record` over the current connection
+    async fn send(&mut self, record: &SpoolRecord) -> Result<(), Error> {
+        match record {
+            SpoolRecord::OpenTimeline { id } => {
+                self.client_mut().open_timeline(*id).await?;
+            }
+            SpoolRecord::TimelineMetadata { attrs } => {
+                let iattrs = self.intern(attrs).await?;
+                self.client_mut().timeline_metadata(iattrs).await?;
+            }
+            SpoolRecord::Event { be_ordering, attrs } => {
+                let ordering = event_ordering(be_ordering);
+                let iattrs = self.intern(attrs).await?;
+                self.client_mut().event(ordering, iattrs).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        if self.spool.is_none() {
+            if self.client.is_none() && !self.unflushed.is_empty() {
+                // An earlier reconnect gave up, have another go before giving up on
+                // what's been held back since
+                if let Err(e) = self.reconnect().await {
+                    warn!(
+                        records = self.unflushed.len(),
+                        "Dropping ingest records that couldn't be sent"
+                    );
+                    self.restart_unflushed();
+                    return Err(e);
+                }
+            }
+            if let Some(client) = self.client.as_mut() {
+                if let Err(e) = client.flush().await {
+                    self.recover(e).await?;
+                    if let Some(client) = self.client.as_mut() {
+                        client.flush().await?;
+                    }
+                }
+            }
+        }
+        // Recovering may have switched to the spool
+        if let Some(spool) = self.spool.as_mut() {
+            return spool.flush();
+        }
+
+        self.restart_unflushed();
+        Ok(())
+    }
+
+    /// Let go of the records held for re-sending, keeping the open timeline so what's
+    /// sent next can be re-sent on it
+    fn restart_unflushed(&mut self) {
+        self.unflushed.clear();
+        if let Some(id) = self.timeline {
+            self.unflushed.push(SpoolRecord::OpenTimeline { id });
+        }
+    }
+
+    /// Reconnect after `err` and re-send everything that wasn't flushed. If that fails,
+    /// spool from now on, or return `err` when there's no spool to fall back to.
+    async fn recover(&mut self, err: Error) -> Result<(), Error> {
+        warn!(error = %err, "Lost the connection to modalityd");
+        self.client = None;
+        self.attrs.clear();
+        match self.reconnect().await {
+            Ok(()) => Ok(()),
+            Err(e) if self.spool_path.is_some() => {
+                warn!(error = %e, "Failed to reconnect to modalityd, spooling to disk");
+                let unflushed = std::mem::take(&mut self.unflushed);
+                self.spool(&unflushed)
+            }
+            Err(_) => Err(err),
+        }
+    }
+
+    /// Try to reconnect according to the reconnect policy, backing off exponentially
+    /// between attempts. Once that gives up, further calls fail straight away until
+    /// the cooldown is over.
+    async fn reconnect(&mut self) -> Result<(), Error> {
+        if self.retry_after.is_some_and(|t| Instant::now() < t) {
+            return Err(Error::NotConnected);
+        }
+        let res = self.reconnect_with_backoff().await;
+        self.retry_after = res.is_err().then(|| Instant::now() + RECONNECT_COOLDOWN);
+        res
+    }
+
+    async fn reconnect_with_backoff(&mut self) -> Result<(), Error> {
+        let policy = self.reconnect;
+        let mut backoff = policy.backoff;
+        let mut last_err = Error::IngestWorkerStopped;
+        for attempt in 1..=policy.attempts {
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+            debug!(attempt, "Reconnecting to modalityd");
+            match self.connection.connect().await {
+                Ok(client) => {
+                    self.client = Some(client);
+                    match self.resend_unflushed().await {
+                        Ok(()) => {
+                            debug!(attempt, "Reconnected to modalityd");
+                            return Ok(());
+                        }
+                        Err(e) => {
+                            self.client = None;
+                            self.attrs.clear();
+                            last_err = e;
+                        }
+                    }
+                }
+                Err(e) => last_err = e,
+            }
+            warn!(attempt, error = %last_err, "Failed to reconnect to modalityd");
+        }
+        Err(last_err)
+    }
+
+    async fn resend_unflushed(&mut self) -> Result<(), Error> {
+        let unflushed = std::mem::take(&mut self.unflushed);
+        let mut res = Ok(());
+        for record in unflushed.iter() {
+            if let Err(e) = self.send(record).await {
+                res = Err(e);
+                break;
+            }
+        }
+        self.unflushed = unflushed;
+        res
+    }
+
+    fn spool(&mut self, records: &[SpoolRecord]) -> Result<(), Error> {
+        let spool = match self.spool.as_mut() {
+            Some(spool) => spool,
+            None => {
+                let path = self.spool_path.as_ref().ok_or(Error::IngestWorkerStopped)?;
+                debug!(path = %path.display(), "Opening spool file");
+                let mut spool = SpoolWriter::open(path)?;
+                // Whatever follows belongs to the timeline that was open when the
+                // connection went away, unless the records open it themselves
+                let opens_timeline =
+                    matches!(records.first(), Some(SpoolRecord::OpenTimeline { .. }));
+                if let (Some(id), false) = (self.timeline, opens_timeline) {
+                    spool.append(&SpoolRecord::OpenTimeline { id })?;
+                }
+                self.spool.insert(spool)
+            }
+        };
+        for record in records {
+            spool.append(record)?;
+        }
+        Ok(())
+    }
+
+    fn client_mut(&mut self) -> &mut C::Connection {
+        self.client.as_mut().expect("connected ingest client")
+    }
+
+    async fn intern(
+        &mut self,
+        attrs: &[(String, AttrVal)],
+    ) -> Result<HashMap<InternedAttrKey, AttrVal>, Error> {
+        let mut iattrs = HashMap::new();
+        for (k, v) in attrs.iter() {
+            iattrs.insert(self.declare_attr_key(k).await?, v.clone());
+        }
+        Ok(iattrs)
+    }
+
+    async fn declare_attr_key(&mut self, k: &str) -> Result<InternedAttrKey, Error> {
+        if let Some(ikey) = self.attrs.get(k) {
+            Ok(*ikey)
+        } else {
+            let ikey = self.client_mut().declare_attr_key(k.to_owned()).await?;
+            self.attrs.insert(k.to_owned(), ikey);
+            Ok(ikey)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spool::tests::TempPath;
+    use std::cell::RefCell;
+    use std::future::Future;
+    use std::io;
+    use std::rc::Rc;
+
+    fn invalid_reason(url: &str) -> String {
+        match parse_ingest_url(url) {
+            Err(Error::InvalidIngestUrl { reason, .. }) => reason,
+            res => panic!("expected an invalid URL error, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_ingest_url_accepts_both_schemes() {
+        let url = parse_ingest_url("modality-ingest://127.0.0.1:14182").unwrap();
+        assert_eq!(url.host_str(), Some("127.0.0.1"));
+        assert_eq!(url.port(), Some(14182));
+        let url = parse_ingest_url(" modality-ingest-tls://modality.lab ").unwrap();
+        assert_eq!(url.scheme(), MODALITY_INGEST_TLS_URL_SCHEME);
+        assert_eq!(url.host_str(), Some("modality.lab"));
+    }
+
+    #[test]
+    fn parse_ingest_url_rejects_other_schemes() {
+        assert!(invalid_reason("http://127.0.0.1:14182").contains("expected a"));
+    }
+
+    #[test]
+    fn parse_ingest_url_rejects_missing_host() {
+        assert_eq!(invalid_reason("modality-ingest:foo"), "missing host");
+    }
+
+    #[test]
+    fn parse_ingest_url_rejects_garbage() {
+        invalid_reason("not a url");
+    }
+
+    /// A stand-in for modalityd that records what each connection sent
+    #[derive(Default)]
+    struct FakeModalityd {
+        /// Refuse connections while set
+        down: bool,
+        /// Fail the next send, breaking the connection it's on
+        fail_next_send: bool,
+        connections: usize,
+        /// Everything received, along with the connection it came in on
+        received: Vec<(usize, Received)>,
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Received {
+        Open(TimelineId),
+        Metadata,
+        Event(u128),
+    }
+
+    type Server = Rc<RefCell<FakeModalityd>>;
+
+    struct FakeConnector(Server);
+
+    impl Connector for FakeConnector {
+        type Connection = FakeConnection;
+
+        async fn connect(&self) -> Result<FakeConnection, Error> {
+            let mut server = self.0.borrow_mut();
+            if server.down {
+                return Err(io::Error::from(io::ErrorKind::ConnectionRefused).into());
+            }
+            server.connections += 1;
+            Ok(FakeConnection {
+                id: server.connections,
+                server: Rc::clone(&self.0),
+                broken: false,
+                next_key: 0,
+            })
+        }
+    }
+
+    struct FakeConnection {
+        id: usize,
+        server: Server,
+        broken: bool,
+        next_key: u32,
+    }
+
+    impl FakeConnection {
+        fn receive(&mut self, received: Option<Received>) -> Result<(), Error> {
+            let mut server = self.server.borrow_mut();
+            if self.broken || std::mem::take(&mut server.fail_next_send) {
+                self.broken = true;
+                return Err(io::Error::from(io::ErrorKind::ConnectionReset).into());
+            }
+            server.received.extend(received.map(|r| (self.id, r)));
+            Ok(())
+        }
+    }
+
+    impl IngestConnection for FakeConnection {
+        async fn open_timeline(&mut self, id: TimelineId) -> Result<(), Error> {
+            self.receive(Some(Received::Open(id)))
+        }
+
+        fn close_timeline(&mut self) {}
+
+        async fn declare_attr_key(&mut self, _key: String) -> Result<InternedAttrKey, Error> {
+            self.receive(None)?;
+            self.next_key += 1;
+            Ok(self.next_key.into())
+        }
+
+        async fn timeline_metadata(
+            &mut self,
+            _attrs: HashMap<InternedAttrKey, AttrVal>,
+        ) -> Result<(), Error> {
+            self.receive(Some(Received::Metadata))
+        }
+
+        async fn event(
+            &mut self,
+            ordering: u128,
+            _attrs: HashMap<InternedAttrKey, AttrVal>,
+        ) -> Result<(), Error> {
+            self.receive(Some(Received::Event(ordering)))
+        }
+
+        async fn flush(&mut self) -> Result<(), Error> {
+            self.receive(None)
+        }
+    }
+
+    /// A worker that's connected to `server`, reconnecting without backing off
+    fn worker(server: &Server, spool_path: Option<PathBuf>) -> Worker<FakeConnector> {
+        let connector = FakeConnector(Rc::clone(server));
+        let client = block_on(connector.connect()).unwrap();
+        let reconnect = ReconnectPolicy {
+            attempts: 2,
+            backoff: Duration::ZERO,
+        };
+        Worker::new(Some(client), connector, reconnect, spool_path)
+    }
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    fn event_attrs() -> Vec<(String, AttrVal)> {
+        vec![("event.name".to_owned(), "step".into())]
+    }
+
+    fn event(ordering: u128) -> Command {
+        Command::Event {
+            ordering,
+            attrs: event_attrs(),
+        }
+    }
+
+    fn received_on(server: &Server, connection: usize) -> Vec<Received> {
+        server
+            .borrow_mut()
+            .received
+            .drain(..)
+            .filter(|(c, _)| *c == connection)
+            .map(|(_, r)| r)
+            .collect()
+    }
+
+    #[test]
+    fn flush_keeps_the_open_timeline_for_resending() {
+        let server = Server::default();
+        let mut w = worker(&server, None);
+        let timeline = TimelineId::allocate();
+        block_on(async {
+            w.handle(Command::OpenTimeline(timeline)).await.unwrap();
+            w.handle(event(0)).await.unwrap();
+            w.flush().await.unwrap();
+        });
+        assert_eq!(w.unflushed, [SpoolRecord::OpenTimeline { id: timeline }]);
+    }
+
+    #[test]
+    fn resends_unflushed_records_in_order_after_reconnecting() {
+        let server = Server::default();
+        let mut w = worker(&server, None);
+        let timeline = TimelineId::allocate();
+        block_on(async {
+            w.handle(Command::OpenTimeline(timeline)).await.unwrap();
+            w.handle(event(0)).await.unwrap();
+            w.flush().await.unwrap();
+            w.handle(Command::TimelineMetadata(event_attrs()))
+                .await
+                .unwrap();
+            w.handle(event(1)).await.unwrap();
+            server.borrow_mut().fail_next_send = true;
+            w.handle(event(2)).await.unwrap();
+            w.flush().await.unwrap();
+        });
+        assert_eq!(server.borrow().connections, 2);
+        assert_eq!(
+            received_on(&server, 2),
+            [
+                Received::Open(timeline),
+                Received::Metadata,
+                Received::Event(1),
+                Received::Event(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn spools_once_reconnecting_fails() {
+        let server = Server::default();
+        let spool = TempPath::new("worker-fallback");
+        let mut w = worker(&server, Some(spool.0.clone()));
+        let timeline = TimelineId::allocate();
+        block_on(async {
+            w.handle(Command::OpenTimeline(timeline)).await.unwrap();
+            w.handle(event(0)).await.unwrap();
+            w.flush().await.unwrap();
+            w.handle(event(1)).await.unwrap();
+            server.borrow_mut().down = true;
+            server.borrow_mut().fail_next_send = true;
+            w.handle(event(2)).await.unwrap();
+            w.handle(event(3)).await.unwrap();
+            w.flush().await.unwrap();
+        });
+        assert_eq!(server.borrow().connections, 1);
+
+        // Everything since the last flush, starting on the timeline that was open
+        let mut reader = SpoolReader::open(&spool.0).unwrap();
+        let mut spooled = Vec::new();
+        while let Some(record) = reader.next_record().unwrap() {
+            spooled.push(record);
+        }
+        assert_eq!(
+            spooled,
+            [
+                SpoolRecord::OpenTimeline { id: timeline },
+                SpoolRecord::event(1, event_attrs()),
+                SpoolRecord::event(2, event_attrs()),
+                SpoolRecord::event(3, event_attrs()),
+            ]
+        );
+    }
+
+    #[test]
+    fn cooldown_drops_the_backlog_but_not_the_open_timeline() {
+        let server = Server::default();
+        let mut w = worker(&server, None);
+        let timeline = TimelineId::allocate();
+        block_on(async {
+            w.handle(Command::OpenTimeline(timeline)).await.unwrap();
+            w.handle(event(0)).await.unwrap();
+            w.flush().await.unwrap();
+            server.borrow_mut().down = true;
+            server.borrow_mut().fail_next_send = true;
+            assert!(matches!(w.handle(event(1)).await, Err(Error::Io(_))));
+            // Held back while disconnected
+            w.handle(event(2)).await.unwrap();
+
+            // modalityd is back, but the cooldown holds off on reconnecting
+            server.borrow_mut().down = false;
+            assert!(matches!(w.flush().await, Err(Error::NotConnected)));
+            assert_eq!(server.borrow().connections, 1);
+            assert_eq!(w.unflushed, [SpoolRecord::OpenTimeline { id: timeline }]);
+
+            // Once it's over, what's sent next goes out on the open timeline
+            w.retry_after = Some(Instant::now());
+            w.handle(event(3)).await.unwrap();
+            w.flush().await.unwrap();
+        });
+        assert_eq!(server.borrow().connections, 2);
+        assert_eq!(
+            received_on(&server, 2),
+            [Received::Open(timeline), Received::Event(3)]
+        );
+    }
+}
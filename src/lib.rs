@@ -1,278 +1,1853 @@
-use crate::error::Error;
+use crate::error::{Error, ErrorContext};
+use crate::ingest::{
+    ConnectionConfig, IngestWorker, PendingFlush, DEFAULT_RECONNECT_ATTEMPTS,
+    DEFAULT_RECONNECT_BACKOFF_SECS, POLL_INTERVAL,
+};
+use crate::listener::ModalityListener;
 use auxon_sdk::{
     api::{AttrKey, AttrVal, Nanoseconds, TimelineId},
-    ingest_client::{dynamic::DynamicIngestClient, IngestClient},
-    ingest_protocol::InternedAttrKey,
     reflector_config::AttrKeyEqValuePair,
 };
 use pyo3::prelude::*;
+use pyo3::types::PyDict;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::time::{Duration, SystemTime};
-use tokio::runtime::{self, Runtime};
-use tracing::debug;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, error, warn};
 use uuid::Uuid;
 
+mod convert;
 mod error;
+mod ingest;
+mod listener;
+mod signals;
+mod spool;
 
 #[pymodule]
 fn modality_client(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<ModalityClient>()?;
+    m.add_class::<ModalityListener>()?;
+    m.add_function(wrap_pyfunction!(replay_spool, m)?)?;
+    m.add_function(wrap_pyfunction!(close_clients, m)?)?;
+    error::add_exceptions(m)?;
+    m.py()
+        .import_bound("atexit")?
+        .call_method1("register", (m.getattr("_close_clients")?,))?;
 
     Ok(())
 }
 
+/// Upload a spool file written by a client that couldn't reach modalityd, keeping the
+/// original timeline ids, event orderings and timestamps. Returns the number of events sent.
+///
+/// Takes the same connection options as `ModalityClient`.
+#[pyfunction]
+#[pyo3(signature = (path, ingest_url=None, auth_token=None, allow_insecure_tls=None, timeout_secs=None))]
+fn replay_spool(
+    py: Python<'_>,
+    path: PathBuf,
+    ingest_url: Option<&str>,
+    auth_token: Option<&str>,
+    allow_insecure_tls: Option<bool>,
+    timeout_secs: Option<f64>,
+) -> Result<u64, Error> {
+    let _ = tracing_subscriber::fmt::try_init();
+    let connection = ConnectionConfig::new(
+        ingest_url,
+        auth_token,
+        allow_insecure_tls,
+        timeout_secs,
+        DEFAULT_RECONNECT_ATTEMPTS,
+        DEFAULT_RECONNECT_BACKOFF_SECS,
+    )?;
+    py.allow_threads(|| ingest::replay_spool(&path, &connection))
+}
+
 type SuiteName = String;
 type TestName = String;
+/// A test's fully qualified long name, e.g. `Top.Sub.Leaf.Boot`
+type TestKey = String;
+
+/// The test that's currently running
+struct ActiveTest {
+    name: TestName,
+    key: TestKey,
+}
+
+/// A keyword whose `keyword_start` has been recorded and not yet its end
+struct OpenKeyword {
+    name: String,
+    started: Instant,
+}
+
+/// A test that's been set up and not yet torn down
+struct OpenTest {
+    name: TestName,
+    suite_name: SuiteName,
+    /// Depth of the suite stack when the test was set up
+    suite_depth: usize,
+    timeline_id: TimelineId,
+    /// Whether a `test_result` has been recorded for the test
+    has_result: bool,
+}
+
+/// A component announced with `start_component` that hasn't ended yet.
+struct Component {
+    name: String,
+    /// Depth of the suite stack when the component was started
+    suite_depth: usize,
+}
+
+/// An entry on the suite stack, from the outermost (top-level) suite down to the innermost.
+struct Suite {
+    name: SuiteName,
+    /// The dotted path from the top-level suite, e.g. `Top.Sub.Leaf`
+    long_name: String,
+    timeline_id: TimelineId,
+    /// Timeline attrs set with the "suite" scope, inherited by the suite's tests and child suites
+    timeline_attrs: Vec<(String, AttrVal)>,
+}
+
+/// Where the most recently emitted event landed
+#[derive(Copy, Clone, Debug)]
+struct EmittedEvent {
+    timeline_id: TimelineId,
+    ordering: u128,
+    nonce: u32,
+}
+
+/// Which timelines `set_timeline_attrs` applies to
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum TimelineAttrScope {
+    /// The active test's timeline
+    Test,
+    /// The innermost suite's timeline, the active test's, and any future timelines in the suite
+    Suite,
+    /// Every open timeline, and all future ones
+    Run,
+}
+
+impl FromStr for TimelineAttrScope {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "test" => Ok(TimelineAttrScope::Test),
+            "suite" => Ok(TimelineAttrScope::Suite),
+            "run" => Ok(TimelineAttrScope::Run),
+            _ => Err(Error::InvalidScope(s.to_owned())),
+        }
+    }
+}
+
+/// Close every client that's still open, registered with `atexit` so buffered events
+/// are sent when the interpreter exits without closing them
+#[pyfunction]
+#[pyo3(name = "_close_clients")]
+fn close_clients(py: Python<'_>) {
+    py.allow_threads(|| {
+        signals::wait_for_pending();
+        for state in live_clients() {
+            let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Err(e) = state.close(DEFAULT_CLOSE_TIMEOUT) {
+                warn!(error = %e, "Failed to close the client at exit");
+            }
+        }
+    });
+}
+
+/// Record an interruption by `signal` on every client's open timelines, flushing
+/// them all within `timeout`. A client that's busy for that long is skipped.
+fn interrupt_clients(signal: &str, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    for state in live_clients() {
+        let Some(mut state) = lock_by(&state, deadline) else {
+            warn!(signal, "Client busy, not recording the interruption on it");
+            continue;
+        };
+        if let Err(e) = state.interrupt(signal, deadline) {
+            warn!(error = %e, signal, "Failed to record the interruption");
+        }
+    }
+}
+
+/// Lock `state`, giving up at `deadline`
+fn lock_by(state: &Mutex<ClientState>, deadline: Instant) -> Option<MutexGuard<'_, ClientState>> {
+    loop {
+        match state.try_lock() {
+            Ok(guard) => return Some(guard),
+            Err(TryLockError::Poisoned(e)) => return Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                thread::sleep(POLL_INTERVAL)
+            }
+            Err(TryLockError::WouldBlock) => return None,
+        }
+    }
+}
+
+fn live_clients() -> Vec<Arc<Mutex<ClientState>>> {
+    CLIENTS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect()
+}
 
 #[pyclass]
 pub struct ModalityClient {
-    rt: Runtime,
-    active_suite: Option<SuiteName>,
-    tests_to_timelines: HashMap<TestName, TimelineId>,
+    state: Arc<Mutex<ClientState>>,
+}
+
+/// The options of a [`ModalityClient`], with the constructor's defaults. See its
+/// constructor for what each one does.
+pub(crate) struct ClientOptions {
+    pub(crate) additional_timeline_attrs: Vec<String>,
+    pub(crate) max_message_len: usize,
+    pub(crate) run_id: Option<String>,
+    pub(crate) previous_run_id: Option<String>,
+    pub(crate) queue_size: usize,
+    pub(crate) spool_path: Option<PathBuf>,
+    pub(crate) ingest_url: Option<String>,
+    pub(crate) auth_token: Option<String>,
+    pub(crate) allow_insecure_tls: Option<bool>,
+    pub(crate) timeout_secs: Option<f64>,
+    pub(crate) reconnect_attempts: u32,
+    pub(crate) reconnect_backoff_secs: f64,
+    pub(crate) fail_open: Option<bool>,
+    pub(crate) handle_signals: Option<bool>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            additional_timeline_attrs: Vec::new(),
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+            run_id: None,
+            previous_run_id: None,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            spool_path: None,
+            ingest_url: None,
+            auth_token: None,
+            allow_insecure_tls: None,
+            timeout_secs: None,
+            reconnect_attempts: DEFAULT_RECONNECT_ATTEMPTS,
+            reconnect_backoff_secs: DEFAULT_RECONNECT_BACKOFF_SECS,
+            fail_open: None,
+            handle_signals: None,
+        }
+    }
+}
+
+/// The state of every client created so far, for closing them at exit
+static CLIENTS: Mutex<Vec<Weak<Mutex<ClientState>>>> = Mutex::new(Vec::new());
+
+/// Everything the client tracks, behind the [`ModalityClient`]'s lock
+struct ClientState {
+    ingest: IngestWorker,
+    suite_stack: Vec<Suite>,
+    active_test: Option<ActiveTest>,
+    keyword_stack: Vec<OpenKeyword>,
+    tests_to_timelines: HashMap<TestKey, OpenTest>,
+    /// The latest attempt number and timeline of every test set up so far
+    test_attempts: HashMap<TestKey, (u32, TimelineId)>,
     extra_timeline_attrs: HashMap<AttrKey, AttrVal>,
+    max_message_len: usize,
+    run_id: String,
+    components: HashMap<u32, Component>,
     global_nonce: u32,
     ordering: u128,
-    client: DynamicIngestClient,
-    attrs: HashMap<String, InternedAttrKey>,
+    bound_timeline: Option<TimelineId>,
+    last_event: Option<EmittedEvent>,
+    /// Log and count ingest errors instead of raising them
+    fail_open: bool,
+    ingest_errors: u64,
+    /// `ingest_errors` as of the last suite teardown summary
+    reported_ingest_errors: u64,
+    last_ingest_error: Option<String>,
+    /// Record interruptions by SIGINT and SIGTERM, from the first suite setup on
+    handle_signals: bool,
+    closed: bool,
 }
 
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_MESSAGE_LEN: usize = 1024;
+const DEFAULT_QUEUE_SIZE: usize = 1024;
 const RUN_ID_ENV_VAR: &str = "MODALITY_RUN_ID";
+const PREVIOUS_RUN_ID_ENV_VAR: &str = "MODALITY_PREVIOUS_RUN_ID";
+const SPOOL_PATH_ENV_VAR: &str = "MODALITY_SPOOL_PATH";
+const FAIL_OPEN_ENV_VAR: &str = "MODALITY_FAIL_OPEN";
+const HANDLE_SIGNALS_ENV_VAR: &str = "MODALITY_HANDLE_SIGNALS";
+/// Timeline attrs the client sets itself, which additional timeline attrs can't override
+const RESERVED_TIMELINE_ATTR_KEYS: &[&str] = &["run_id", "id", "name", "clock_style"];
+const DEFAULT_CLOSE_TIMEOUT_SECS: f64 = 10.0;
+const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long suite teardown waits on the flush in fail-open mode, so an unreachable
+/// modalityd doesn't hold up the run
+const FAIL_OPEN_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+/// `event.test.result.code` of each test result
+const PASSED_RESULT_CODE: i64 = 0;
+const FAILED_RESULT_CODE: i64 = 1;
+const SKIPPED_RESULT_CODE: i64 = 2;
+const NOT_RUN_RESULT_CODE: i64 = 3;
+/// `event.test.result.code` of a test that was never torn down
+const ABORTED_RESULT_CODE: i64 = 4;
 
 #[pymethods]
 impl ModalityClient {
+    /// Connect to modalityd.
+    ///
+    /// `ingest_url` (e.g. `modality-ingest-tls://modality.lab:14184`), `auth_token` (hex),
+    /// `allow_insecure_tls` and `timeout_secs` override the standard modality
+    /// configuration, which is used for anything left unset.
+    ///
+    /// When the connection fails, the client makes up to `reconnect_attempts` attempts to
+    /// reconnect, waiting `reconnect_backoff_secs` before the first and doubling that each
+    /// time, and then re-sends everything since the last flush.
+    ///
+    /// With `fail_open` (or `MODALITY_FAIL_OPEN=1`), ingest and connection errors are
+    /// logged and counted instead of raised, including failing to connect here, and a
+    /// summary is logged at each suite teardown.
+    ///
+    /// With `handle_signals` (or `MODALITY_HANDLE_SIGNALS=1`), a SIGINT or SIGTERM records
+    /// a `run_interrupted` event on every open timeline and flushes before the signal
+    /// takes its usual course. The handlers are installed at the first suite setup, so
+    /// they run ahead of any Robot has installed for the run.
     #[new]
-    pub fn new(additional_timeline_attrs: Option<Vec<String>>) -> Result<ModalityClient, Error> {
-        tracing_subscriber::fmt::init();
-        let rt = runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()?;
-        let client = rt
-            .block_on(IngestClient::connect_with_standard_config(
-                CLIENT_TIMEOUT,
-                None,
-                None,
-            ))?
-            .into();
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (additional_timeline_attrs=None, max_message_len=DEFAULT_MAX_MESSAGE_LEN, run_id=None, previous_run_id=None, queue_size=DEFAULT_QUEUE_SIZE, spool_path=None, ingest_url=None, auth_token=None, allow_insecure_tls=None, timeout_secs=None, reconnect_attempts=DEFAULT_RECONNECT_ATTEMPTS, reconnect_backoff_secs=DEFAULT_RECONNECT_BACKOFF_SECS, fail_open=None, handle_signals=None))]
+    pub fn new(
+        py: Python<'_>,
+        additional_timeline_attrs: Option<Vec<String>>,
+        max_message_len: usize,
+        run_id: Option<String>,
+        previous_run_id: Option<String>,
+        queue_size: usize,
+        spool_path: Option<PathBuf>,
+        ingest_url: Option<String>,
+        auth_token: Option<String>,
+        allow_insecure_tls: Option<bool>,
+        timeout_secs: Option<f64>,
+        reconnect_attempts: u32,
+        reconnect_backoff_secs: f64,
+        fail_open: Option<bool>,
+        handle_signals: Option<bool>,
+    ) -> Result<ModalityClient, Error> {
+        let options = ClientOptions {
+            additional_timeline_attrs: additional_timeline_attrs.unwrap_or_default(),
+            max_message_len,
+            run_id,
+            previous_run_id,
+            queue_size,
+            spool_path,
+            ingest_url,
+            auth_token,
+            allow_insecure_tls,
+            timeout_secs,
+            reconnect_attempts,
+            reconnect_backoff_secs,
+            fail_open,
+            handle_signals,
+        };
+        Self::with_options(py, options)
+    }
+
+    /// The run id attached to every timeline, suite and component event of this client
+    #[getter]
+    pub fn run_id(&self, py: Python<'_>) -> String {
+        self.with_state(py, |s| s.run_id.clone())
+    }
+
+    /// The number of ingest errors ignored so far in fail-open mode
+    #[getter]
+    pub fn ingest_error_count(&self, py: Python<'_>) -> u64 {
+        self.with_state(py, |s| s.ingest_errors)
+    }
+
+    #[pyo3(signature = (suite_name, long_name=None, suite_id=None, source=None, documentation=None))]
+    pub fn on_suite_setup(
+        &self,
+        py: Python<'_>,
+        suite_name: &str,
+        long_name: Option<&str>,
+        suite_id: Option<&str>,
+        source: Option<&str>,
+        documentation: Option<&str>,
+    ) -> PyResult<()> {
+        self.try_with_state(py, |s| {
+            s.on_suite_setup(suite_name, long_name, suite_id, source, documentation)
+        })
+    }
+
+    /// Record the outcome of the innermost suite, `status` being one of
+    /// Robot's `PASS`, `FAIL` or `SKIP`.
+    #[pyo3(signature = (status, message=None))]
+    pub fn on_suite_result(
+        &self,
+        py: Python<'_>,
+        status: &str,
+        message: Option<&str>,
+    ) -> PyResult<()> {
+        self.try_with_state(py, |s| s.on_suite_result(status, message))
+    }
+
+    pub fn on_suite_teardown(&self, py: Python<'_>) -> PyResult<()> {
+        let teardown = self.try_with_state(py, |s| s.on_suite_teardown())?;
+        let Some((suite_path, flush)) = teardown else {
+            return Ok(());
+        };
+        // Other threads can keep recording while the flush catches up
+        let res = py.allow_threads(|| flush.and_then(PendingFlush::wait));
+        self.finish(py, |s| {
+            let res = s.fail_open(res);
+            s.report_ingest_errors(&suite_path);
+            res
+        })
+    }
+
+    /// Block until every event recorded so far has been sent to modalityd
+    pub fn flush(&self, py: Python<'_>) -> PyResult<()> {
+        let flush = self.try_with_state(py, |s| s.ingest.start_flush(None).map(Some))?;
+        let Some(flush) = flush else {
+            return Ok(());
+        };
+        // Waited on with the lock released, as it can take a full reconnect
+        let res = py.allow_threads(|| flush.wait());
+        self.finish(py, |_| res)
+    }
+
+    /// Finish the run: record any open tests as aborted, tear down every open suite,
+    /// and flush, waiting at most `timeout_secs` for modalityd. Closing an already
+    /// closed client does nothing, and any other call on it raises.
+    ///
+    /// The client is also closed when used as a context manager, when it's garbage
+    /// collected, and at interpreter exit.
+    #[pyo3(signature = (timeout_secs=DEFAULT_CLOSE_TIMEOUT_SECS))]
+    pub fn close(&self, py: Python<'_>, timeout_secs: f64) -> PyResult<()> {
+        let timeout = Duration::try_from_secs_f64(timeout_secs)
+            .ok()
+            .filter(|t| !t.is_zero())
+            .ok_or(Error::InvalidTimeout(timeout_secs))?;
+        // Checked and closed under the one lock, as another thread or the drop thread
+        // may be closing the client too
+        self.finish(py, |s| s.close(timeout))
+    }
+
+    pub fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    /// Close the client. When the block raised, a failure to close is logged rather
+    /// than raised so it doesn't hide the original exception.
+    pub fn __exit__(
+        &self,
+        py: Python<'_>,
+        exc_type: Option<&Bound<'_, PyAny>>,
+        _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<bool> {
+        match self.close(py, DEFAULT_CLOSE_TIMEOUT_SECS) {
+            Err(e) if exc_type.is_some() => {
+                warn!(error = %e, "Failed to close the client");
+            }
+            res => res?,
+        }
+        Ok(false)
+    }
+
+    /// Start a new timeline for `test_name`. The optional test details are recorded as
+    /// timeline attributes, with one boolean `tag.<tag>` attribute per tag.
+    ///
+    /// Tests are tracked by their `long_name`, which defaults to `test_name` under the
+    /// innermost suite's path. Setting up a test while one with the same long name is
+    /// still open is reported, and the new setup gets a fresh timeline.
+    ///
+    /// Each setup of the same test within this client counts as a new attempt, linked
+    /// to the previous attempt's timeline. `attempt` overrides the count, and
+    /// `original_timeline_id` links a retry to a timeline from an earlier run.
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (test_name, tags=None, documentation=None, source=None, lineno=None, timeout=None, long_name=None, attempt=None, original_timeline_id=None))]
+    pub fn on_test_setup(
+        &self,
+        py: Python<'_>,
+        test_name: &str,
+        tags: Option<Vec<String>>,
+        documentation: Option<&str>,
+        source: Option<&str>,
+        lineno: Option<i64>,
+        timeout: Option<&str>,
+        long_name: Option<&str>,
+        attempt: Option<u32>,
+        original_timeline_id: Option<&str>,
+    ) -> PyResult<()> {
+        self.try_with_state(py, |s| {
+            s.on_test_setup(
+                test_name,
+                tags,
+                documentation,
+                source,
+                lineno,
+                timeout,
+                long_name,
+                attempt,
+                original_timeline_id,
+            )
+        })
+    }
+
+    pub fn on_test_teardown(&self, py: Python<'_>, test_name: &str) -> PyResult<()> {
+        self.try_with_state(py, |s| s.on_test_teardown(test_name))
+    }
+
+    pub fn on_test_passed(&self, py: Python<'_>, test_name: &str) -> PyResult<()> {
+        self.try_with_state(py, |s| s.on_test_passed(test_name))
+    }
+
+    /// Record a failed test, optionally with the failure message, the Python exception
+    /// type and a traceback. Messages and tracebacks are truncated to `max_message_len`.
+    #[pyo3(signature = (test_name, message=None, error_type=None, traceback=None))]
+    pub fn on_test_failed(
+        &self,
+        py: Python<'_>,
+        test_name: &str,
+        message: Option<&str>,
+        error_type: Option<&str>,
+        traceback: Option<&str>,
+    ) -> PyResult<()> {
+        self.try_with_state(py, |s| {
+            s.on_test_failed(test_name, message, error_type, traceback)
+        })
+    }
+
+    /// Record a skipped test, e.g. from `Skip`, `Skip If` or `--skiponfailure`.
+    #[pyo3(signature = (test_name, reason=None))]
+    pub fn on_test_skipped(
+        &self,
+        py: Python<'_>,
+        test_name: &str,
+        reason: Option<&str>,
+    ) -> PyResult<()> {
+        self.try_with_state(py, |s| s.on_test_skipped(test_name, reason))
+    }
+
+    /// Record a test that was not run, e.g. because of `--dryrun` or `--exitonfailure`.
+    #[pyo3(signature = (test_name, reason=None))]
+    pub fn on_test_not_run(
+        &self,
+        py: Python<'_>,
+        test_name: &str,
+        reason: Option<&str>,
+    ) -> PyResult<()> {
+        self.try_with_state(py, |s| s.on_test_not_run(test_name, reason))
+    }
+
+    #[pyo3(signature = (keyword_name, library=None, args=None))]
+    pub fn start_keyword(
+        &self,
+        py: Python<'_>,
+        keyword_name: &str,
+        library: Option<&str>,
+        args: Option<Vec<String>>,
+    ) -> PyResult<()> {
+        self.try_with_state(py, |s| s.start_keyword(keyword_name, library, args))
+    }
+
+    #[pyo3(signature = (keyword_name, library=None, status=None))]
+    pub fn end_keyword(
+        &self,
+        py: Python<'_>,
+        keyword_name: &str,
+        library: Option<&str>,
+        status: Option<&str>,
+    ) -> PyResult<()> {
+        self.try_with_state(py, |s| s.end_keyword(keyword_name, library, status))
+    }
+
+    /// Record that a component, e.g. a DUT or a simulator, has started, returning the
+    /// nonce to pass to `end_component` and `component_state`.
+    ///
+    /// The nonce is that of the `start_component` event. Every event the client records
+    /// takes the next nonce, so component nonces aren't consecutive.
+    pub fn start_component(&self, py: Python<'_>, component_name: &str) -> PyResult<u32> {
+        self.try_with_state(py, |s| s.start_component(component_name))
+    }
+
+    /// Record that the component started with `nonce` has stopped, e.g. with a status
+    /// of `stopped` or `crashed`.
+    #[pyo3(signature = (nonce, status=None))]
+    pub fn end_component(&self, py: Python<'_>, nonce: u32, status: Option<&str>) -> PyResult<()> {
+        self.try_with_state(py, |s| s.end_component(nonce, status))
+    }
+
+    /// Record a state change, such as `restarted`, of a component that is still running.
+    pub fn component_state(&self, py: Python<'_>, nonce: u32, state: &str) -> PyResult<()> {
+        self.try_with_state(py, |s| s.component_state(nonce, state))
+    }
+
+    /// Record a custom event named `name` on the active test's timeline, or on the
+    /// innermost suite's timeline when no test is active.
+    ///
+    /// Attribute keys are prefixed with `event.` as needed, and values may be `int`,
+    /// `float`, `bool`, `str`, `bytes`, `datetime` or `timedelta`.
+    #[pyo3(signature = (name, attrs=None))]
+    pub fn record_event(
+        &self,
+        py: Python<'_>,
+        name: &str,
+        attrs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<()> {
+        let attrs = match attrs {
+            Some(dict) => convert::attrs_from_dict(dict, "event.")?,
+            None => Vec::new(),
+        };
+        self.try_with_state(py, |s| s.user_event(name, attrs))?;
+        Ok(())
+    }
+
+    /// Record an event that was caused by the event with `remote_nonce` on the
+    /// timeline `remote_timeline_id`, e.g. a DUT event that triggered this test step.
+    /// Takes the same `attrs` as `record_event`.
+    #[pyo3(signature = (remote_timeline_id, remote_nonce, name, attrs=None))]
+    pub fn record_interaction(
+        &self,
+        py: Python<'_>,
+        remote_timeline_id: &str,
+        remote_nonce: u64,
+        name: &str,
+        attrs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<()> {
+        let remote_timeline_id = parse_timeline_id(remote_timeline_id)?;
+        let attrs = match attrs {
+            Some(dict) => convert::attrs_from_dict(dict, "event.")?,
+            None => Vec::new(),
+        };
+        self.try_with_state(py, |s| {
+            s.record_interaction(remote_timeline_id, remote_nonce, name, attrs)
+        })?;
+        Ok(())
+    }
+
+    /// The `(timeline_id, nonce)` of the most recently emitted event, for the other
+    /// side of an interaction to reference as its remote timeline id and remote nonce.
+    pub fn last_event_nonce(&self, py: Python<'_>) -> Option<(String, u32)> {
+        self.with_state(py, |s| s.last_event_nonce())
+    }
+
+    /// The `(timeline_id, ordering)` of the most recently emitted event
+    pub fn last_event_coordinate(&self, py: Python<'_>) -> Option<(String, u128)> {
+        self.with_state(py, |s| s.last_event_coordinate())
+    }
+
+    /// The id of the timeline that events currently go to: the active test's, or the
+    /// innermost suite's when no test is active
+    pub fn current_timeline_id(&self, py: Python<'_>) -> Option<String> {
+        self.with_state(py, |s| s.current_timeline_id())
+    }
+
+    /// The id of the innermost suite's timeline
+    pub fn suite_timeline_id(&self, py: Python<'_>) -> Option<String> {
+        self.with_state(py, |s| s.suite_timeline_id())
+    }
+
+    /// The timeline id of an open test, by name or long name
+    pub fn timeline_id_for(&self, py: Python<'_>, test_name: &str) -> Option<String> {
+        self.with_state(py, |s| s.timeline_id_for(test_name))
+    }
+
+    /// Add or update timeline attributes mid-run, e.g. a DUT firmware version learned
+    /// during a test. Keys are prefixed with `timeline.` as needed and values are
+    /// converted as in `record_event`.
+    ///
+    /// `scope` is one of:
+    /// * `"test"`: the active test's timeline
+    /// * `"suite"`: the innermost suite's timeline, the active test's timeline, and the
+    ///   timelines of tests and child suites started later in the suite
+    /// * `"run"`: every open timeline and all timelines started later
+    #[pyo3(signature = (attrs, scope="test"))]
+    pub fn set_timeline_attrs(
+        &self,
+        py: Python<'_>,
+        attrs: &Bound<'_, PyDict>,
+        scope: &str,
+    ) -> PyResult<()> {
+        let scope = TimelineAttrScope::from_str(scope)?;
+        let attrs = convert::attrs_from_dict(attrs, "timeline.")?;
+        self.try_with_state(py, |s| s.set_timeline_attrs(attrs, scope))?;
+        Ok(())
+    }
+}
+
+impl ModalityClient {
+    /// Connect to modalityd, as the constructor does
+    pub(crate) fn with_options(py: Python<'_>, options: ClientOptions) -> Result<Self, Error> {
+        let connection = ConnectionConfig::new(
+            options.ingest_url.as_deref(),
+            options.auth_token.as_deref(),
+            options.allow_insecure_tls,
+            options.timeout_secs,
+            options.reconnect_attempts,
+            options.reconnect_backoff_secs,
+        )?;
+        let state = py.allow_threads(|| ClientState::new(options, connection))?;
+        let state = Arc::new(Mutex::new(state));
+        let mut clients = CLIENTS.lock().unwrap_or_else(PoisonError::into_inner);
+        clients.retain(|c| c.strong_count() > 0);
+        clients.push(Arc::downgrade(&state));
+        Ok(Self { state })
+    }
+
+    /// Run `f` on the client state with the GIL released, so other Python threads
+    /// keep running while this one waits on the lock or on a full ingest queue.
+    /// `f` must not touch any Python objects.
+    fn with_state<T, F>(&self, py: Python<'_>, f: F) -> T
+    where
+        T: Send,
+        F: FnOnce(&mut ClientState) -> T + Send,
+    {
+        py.allow_threads(|| {
+            // A panic on another thread doesn't leave the state in a state worth
+            // refusing to touch, so carry on past the poison
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            f(&mut state)
+        })
+    }
+
+    /// Like `with_state`, but in fail-open mode ingest errors are logged and counted
+    /// rather than raised, and any other error is raised along with the active suite
+    /// and test
+    fn try_with_state<T, F>(&self, py: Python<'_>, f: F) -> PyResult<T>
+    where
+        T: Send + Default,
+        F: FnOnce(&mut ClientState) -> Result<T, Error> + Send,
+    {
+        self.finish(py, |s| {
+            if s.closed {
+                Err(Error::ClientClosed)
+            } else {
+                f(s)
+            }
+        })
+    }
+
+    /// Like `try_with_state`, but also runs on a closed client, for finishing off
+    /// work started before the lock was released
+    fn finish<T, F>(&self, py: Python<'_>, f: F) -> PyResult<T>
+    where
+        T: Send + Default,
+        F: FnOnce(&mut ClientState) -> Result<T, Error> + Send,
+    {
+        self.with_state(py, |s| {
+            let res = f(s);
+            s.fail_open(res).map_err(|e| (e, s.error_context()))
+        })
+        .map_err(|(e, ctx)| e.into_py_err(py, ctx))
+    }
+}
+
+impl Drop for ModalityClient {
+    fn drop(&mut self) {
+        // Best effort for a client that's garbage collected without being closed. This
+        // runs with the GIL held, so close on a thread of its own rather than holding
+        // up every other Python thread while flushing.
+        if self.state.try_lock().is_ok_and(|s| s.closed) {
+            return;
+        }
+        let state = Arc::clone(&self.state);
+        let res = thread::Builder::new()
+            .name("modality-close".to_owned())
+            .spawn(move || {
+                let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+                if let Err(e) = state.close(DEFAULT_CLOSE_TIMEOUT) {
+                    warn!(error = %e, "Failed to close the client");
+                }
+            });
+        if let Err(e) = res {
+            warn!(error = %e, "Failed to start closing the client");
+        }
+    }
+}
+
+impl ClientState {
+    fn new(options: ClientOptions, connection: ConnectionConfig) -> Result<Self, Error> {
+        let ClientOptions {
+            additional_timeline_attrs,
+            max_message_len,
+            run_id,
+            previous_run_id,
+            queue_size,
+            spool_path,
+            fail_open,
+            handle_signals,
+            ..
+        } = options;
+        // The listener and any user-constructed clients share the global subscriber
+        let _ = tracing_subscriber::fmt::try_init();
+        let spool_path =
+            spool_path.or_else(|| std::env::var_os(SPOOL_PATH_ENV_VAR).map(PathBuf::from));
+        let fail_open = fail_open.unwrap_or_else(|| env_flag(FAIL_OPEN_ENV_VAR));
+        let handle_signals = handle_signals.unwrap_or_else(|| env_flag(HANDLE_SIGNALS_ENV_VAR));
         let mut extra_timeline_attrs = HashMap::new();
-        for attr in additional_timeline_attrs.unwrap_or_default() {
+        for attr in additional_timeline_attrs {
             let kv = AttrKeyEqValuePair::from_str(&attr)?;
+            let key = kv.0.as_ref();
+            if RESERVED_TIMELINE_ATTR_KEYS.contains(&key.strip_prefix("timeline.").unwrap_or(key)) {
+                return Err(Error::ReservedTimelineAttr(key.to_owned()));
+            }
             extra_timeline_attrs.insert(kv.0, kv.1);
         }
+        // One run id for every timeline of this client, so a whole robot invocation
+        // can be grouped together
+        let run_id = run_id
+            .or_else(|| std::env::var(RUN_ID_ENV_VAR).ok())
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        debug!(run_id, "Using run id");
+        // Re-executions (e.g. `--rerunfailed`) link back to the run they're retrying
+        if let Some(previous_run_id) =
+            previous_run_id.or_else(|| std::env::var(PREVIOUS_RUN_ID_ENV_VAR).ok())
+        {
+            extra_timeline_attrs.insert(
+                "robot_framework.previous_run_id".into(),
+                previous_run_id.into(),
+            );
+        }
+        // Connect last, once every argument has been validated
+        let ingest = IngestWorker::spawn(queue_size, connection, spool_path, fail_open)?;
 
         Ok(Self {
-            rt,
-            active_suite: None,
+            ingest,
+            suite_stack: Default::default(),
+            active_test: None,
+            keyword_stack: Default::default(),
             tests_to_timelines: Default::default(),
+            test_attempts: Default::default(),
             extra_timeline_attrs,
+            max_message_len,
+            run_id,
+            components: Default::default(),
             global_nonce: 1,
             ordering: 0,
-            client,
-            attrs: Default::default(),
+            bound_timeline: None,
+            last_event: None,
+            fail_open,
+            ingest_errors: 0,
+            reported_ingest_errors: 0,
+            last_ingest_error: None,
+            handle_signals,
+            closed: false,
         })
     }
 
-    pub fn on_suite_setup(&mut self, suite_name: &str) -> Result<(), Error> {
-        if self.active_suite.is_some() {
-            self.on_suite_teardown()?;
+    fn on_suite_setup(
+        &mut self,
+        suite_name: &str,
+        long_name: Option<&str>,
+        suite_id: Option<&str>,
+        source: Option<&str>,
+        documentation: Option<&str>,
+    ) -> Result<(), Error> {
+        if self.handle_signals {
+            signals::install();
+        }
+        let parent_long_name = self.suite_stack.last().map(|s| s.long_name.clone());
+        let long_name = match (long_name, parent_long_name.as_ref()) {
+            (Some(long_name), _) => long_name.to_owned(),
+            (None, Some(parent)) => format!("{parent}.{suite_name}"),
+            (None, None) => suite_name.to_owned(),
+        };
+        debug!(suite_name, long_name, "on_suite_setup");
+
+        let timeline_id = TimelineId::allocate();
+        self.open_timeline(timeline_id)?;
+
+        let mut attrs: Vec<(String, AttrVal)> = vec![
+            ("timeline.name".into(), "robot_framework.suite".into()),
+            (
+                "timeline.robot_framework.suite.name".into(),
+                suite_name.into(),
+            ),
+            (
+                "timeline.robot_framework.suite.path".into(),
+                long_name.as_str().into(),
+            ),
+            (
+                "timeline.robot_framework.suite.depth".into(),
+                (self.suite_stack.len() as i64).into(),
+            ),
+            ("timeline.id".into(), timeline_id.into()),
+            ("timeline.clock_style".into(), "utc".into()),
+            ("timeline.run_id".into(), self.run_id.as_str().into()),
+        ];
+        if let Some(parent) = parent_long_name.as_ref() {
+            attrs.push((
+                "timeline.robot_framework.suite.parent".into(),
+                parent.into(),
+            ));
+        }
+        if let Some(parent) = self.suite_stack.last() {
+            attrs.push((
+                "timeline.robot_framework.suite.parent.timeline_id".into(),
+                parent.timeline_id.into(),
+            ));
+        }
+        if let Some(suite_id) = suite_id {
+            attrs.push(("timeline.robot_framework.suite.id".into(), suite_id.into()));
+        }
+        if let Some(source) = source {
+            attrs.push((
+                "timeline.robot_framework.suite.source".into(),
+                source.into(),
+            ));
+        }
+        if let Some(documentation) = documentation {
+            attrs.push((
+                "timeline.robot_framework.suite.documentation".into(),
+                documentation.into(),
+            ));
+        }
+        attrs.extend(self.inherited_timeline_attrs());
+        timeline_metadata(self, attrs)?;
+
+        let mut attrs: Vec<(&str, AttrVal)> = vec![
+            ("event.name", "suite_setup".into()),
+            ("event.run_id", self.run_id.as_str().into()),
+            ("event.suite.name", suite_name.into()),
+            ("event.suite.path", long_name.as_str().into()),
+            ("event.suite.depth", (self.suite_stack.len() as i64).into()),
+        ];
+        if let Some(parent) = parent_long_name {
+            attrs.push(("event.suite.parent", parent.into()));
         }
-        debug!(suite_name, "on_suite_setup");
-        self.active_suite = Some(suite_name.into());
+        event(self, attrs)?;
+
+        self.suite_stack.push(Suite {
+            name: suite_name.to_owned(),
+            long_name,
+            timeline_id,
+            timeline_attrs: Vec::new(),
+        });
         Ok(())
     }
 
-    pub fn on_suite_teardown(&mut self) -> Result<(), Error> {
-        if let Some(suite_name) = self.active_suite.take() {
-            debug!(suite_name, "on_suite_teardown");
-            self.client.close_timeline();
-            self.rt.block_on(self.client.flush())?;
+    fn on_suite_result(&mut self, status: &str, message: Option<&str>) -> Result<(), Error> {
+        let suite = self.suite_stack.last().ok_or(Error::NoSuiteActive)?;
+        let result = match status.to_ascii_uppercase().as_str() {
+            "PASS" => "passed",
+            "FAIL" => "failed",
+            "SKIP" => "skipped",
+            _ => return Err(Error::InvalidStatus(status.to_owned())),
+        };
+        debug!(suite.name, result, "on_suite_result");
+
+        let timeline_id = suite.timeline_id;
+        let mut attrs: Vec<(&str, AttrVal)> = vec![
+            ("event.name", "suite_result".into()),
+            ("event.run_id", self.run_id.as_str().into()),
+            ("event.suite.name", suite.name.as_str().into()),
+            ("event.suite.path", suite.long_name.as_str().into()),
+            ("event.suite.result", result.into()),
+        ];
+        if let Some(message) = message.filter(|m| !m.is_empty()) {
+            attrs.push(("event.suite.result.message", message.into()));
         }
+        self.open_timeline(timeline_id)?;
+        event(self, attrs)?;
         Ok(())
     }
 
-    pub fn on_test_setup(&mut self, test_name: &str) -> Result<(), Error> {
-        let suite_name = self.active_suite.as_ref().ok_or(Error::NoSuiteActive)?;
+    /// Tear down the innermost suite and queue a flush, to be waited on with the lock
+    /// released. Returns the suite's path and the flush, or `None` when no suite is
+    /// active.
+    #[allow(clippy::type_complexity)]
+    fn on_suite_teardown(
+        &mut self,
+    ) -> Result<Option<(String, Result<PendingFlush, Error>)>, Error> {
+        let Some(suite_path) = self.teardown_suite()? else {
+            return Ok(None);
+        };
+        // Bounded in fail-open mode, so an unreachable modalityd doesn't hold up the run
+        let timeout = self.fail_open.then_some(FAIL_OPEN_FLUSH_TIMEOUT);
+        Ok(Some((suite_path, self.ingest.start_flush(timeout))))
+    }
 
-        let mut timeline_is_new = false;
-        let timeline_id = *self
-            .tests_to_timelines
-            .entry(test_name.to_owned())
-            .or_insert_with(|| {
-                timeline_is_new = true;
-                TimelineId::allocate()
-            });
-        self.rt.block_on(self.client.open_timeline(timeline_id))?;
+    /// Emit the innermost suite's teardown and pop it off the stack, returning its
+    /// path, or `None` when no suite is active
+    fn teardown_suite(&mut self) -> Result<Option<String>, Error> {
+        let Some(suite) = self.suite_stack.pop() else {
+            return Ok(None);
+        };
+        debug!(suite.name, suite.long_name, "on_suite_teardown");
+        let suite_path = suite.long_name.clone();
+        let depth = self.suite_stack.len();
 
-        if timeline_is_new {
-            let mut attrs = HashMap::new();
-            let run_id = if let Ok(env_val) = std::env::var(RUN_ID_ENV_VAR) {
-                env_val.into()
-            } else {
-                Uuid::new_v4().to_string().into()
-            };
-            attrs.insert(
-                self.rt.block_on(declare_attr_key(
-                    "timeline.name",
-                    &mut self.client,
-                    &mut self.attrs,
-                ))?,
-                "robot_framework".into(),
-            );
-            attrs.insert(
-                self.rt.block_on(declare_attr_key(
-                    "timeline.robot_framework.suite.name",
-                    &mut self.client,
-                    &mut self.attrs,
-                ))?,
-                suite_name.into(),
-            );
-            attrs.insert(
-                self.rt.block_on(declare_attr_key(
-                    "timeline.robot_framework.test.name",
-                    &mut self.client,
-                    &mut self.attrs,
-                ))?,
-                test_name.into(),
-            );
-            attrs.insert(
-                self.rt.block_on(declare_attr_key(
-                    "timeline.id",
-                    &mut self.client,
-                    &mut self.attrs,
-                ))?,
-                timeline_id.into(),
-            );
-            attrs.insert(
-                self.rt.block_on(declare_attr_key(
-                    "timeline.clock_style",
-                    &mut self.client,
-                    &mut self.attrs,
-                ))?,
-                "utc".into(),
-            );
-            attrs.insert(
-                self.rt.block_on(declare_attr_key(
-                    "timeline.run_id",
-                    &mut self.client,
-                    &mut self.attrs,
-                ))?,
-                run_id,
-            );
+        // Tests set up within this suite and never torn down were cut short
+        self.abort_open_tests(depth)?;
 
-            for (k, v) in self.extra_timeline_attrs.iter() {
-                attrs.insert(
-                    self.rt.block_on(declare_attr_key(
-                        &format!("timeline.{}", k),
-                        &mut self.client,
-                        &mut self.attrs,
-                    ))?,
-                    v.clone(),
-                );
-            }
+        self.open_timeline(suite.timeline_id)?;
 
-            self.rt.block_on(self.client.timeline_metadata(attrs))?;
+        // Anything started within this suite and never stopped has leaked
+        let mut leaked: Vec<u32> = self
+            .components
+            .iter()
+            .filter(|(_, c)| c.suite_depth > depth)
+            .map(|(nonce, _)| *nonce)
+            .collect();
+        leaked.sort_unstable();
+        for nonce in leaked {
+            let component = self.components.remove(&nonce).expect("leaked component");
+            event(
+                self,
+                [
+                    ("event.name", "component_leaked".into()),
+                    ("event.run_id", self.run_id.as_str().into()),
+                    ("event.component_nonce", nonce.into()),
+                    ("event.component_name", component.name.into()),
+                    ("event.suite.path", suite.long_name.as_str().into()),
+                ],
+            )?;
         }
 
-        event(
-            self,
-            [
-                ("event.name", "test_setup".into()),
-                ("event.suite.name", suite_name.into()),
-                ("event.test.name", test_name.into()),
-            ],
-        )?;
+        let mut attrs: Vec<(&str, AttrVal)> = vec![
+            ("event.name", "suite_teardown".into()),
+            ("event.run_id", self.run_id.as_str().into()),
+            ("event.suite.name", suite.name.into()),
+            ("event.suite.path", suite.long_name.into()),
+            ("event.suite.depth", (self.suite_stack.len() as i64).into()),
+        ];
+        if let Some(parent) = self.suite_stack.last() {
+            attrs.push(("event.suite.parent", parent.long_name.as_str().into()));
+        }
+        event(self, attrs)?;
 
-        Ok(())
+        self.close_timeline()?;
+        Ok(Some(suite_path))
     }
 
-    pub fn on_test_teardown(&mut self, test_name: &str) -> Result<(), Error> {
-        let suite_name = self.active_suite.as_ref().ok_or(Error::NoSuiteActive)?;
+    /// Abort any open tests, tear down every suite and flush, then stop the ingest
+    /// worker, giving up on whatever hasn't been sent once `timeout` has passed
+    fn close(&mut self, timeout: Duration) -> Result<(), Error> {
+        if self.closed {
+            return Ok(());
+        }
+        debug!("close");
+        self.closed = true;
+        let deadline = Instant::now() + timeout;
 
-        if let Some(timeline_id) = self.tests_to_timelines.remove(test_name) {
-            self.rt.block_on(self.client.open_timeline(timeline_id))?;
+        let res = self.with_deadline(deadline, |s| s.teardown_all(deadline));
+        self.ingest
+            .shutdown(deadline.saturating_duration_since(Instant::now()));
+        res
+    }
+
+    /// Tear down every suite, aborting their open tests, and flush by `deadline`
+    fn teardown_all(&mut self, deadline: Instant) -> Result<(), Error> {
+        while self.teardown_suite()?.is_some() {}
+        self.ingest
+            .flush_timeout(deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Record an interruption by `signal` on every open timeline and flush, giving up
+    /// at `deadline`
+    fn interrupt(&mut self, signal: &str, deadline: Instant) -> Result<(), Error> {
+        if self.closed {
+            return Ok(());
+        }
+        self.with_deadline(deadline, |s| s.record_interruption(signal, deadline))
+    }
+
+    fn record_interruption(&mut self, signal: &str, deadline: Instant) -> Result<(), Error> {
+        let timelines: Vec<TimelineId> = self
+            .suite_stack
+            .iter()
+            .map(|s| s.timeline_id)
+            .chain(self.tests_to_timelines.values().map(|t| t.timeline_id))
+            .collect();
+        for timeline_id in timelines {
+            self.open_timeline(timeline_id)?;
             event(
                 self,
                 [
-                    ("event.name", "test_teardown".into()),
-                    ("event.suite.name", suite_name.into()),
-                    ("event.test.name", test_name.into()),
+                    ("event.name", "run_interrupted".into()),
+                    ("event.run_id", self.run_id.as_str().into()),
+                    ("event.signal", signal.into()),
                 ],
             )?;
         }
-        Ok(())
+        self.ingest
+            .flush_timeout(deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Run `f` with sends to the ingest worker giving up at `deadline` rather than
+    /// waiting on a full queue
+    fn with_deadline<T>(
+        &mut self,
+        deadline: Instant,
+        f: impl FnOnce(&mut Self) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        self.ingest.set_deadline(Some(deadline));
+        let res = f(self);
+        self.ingest.set_deadline(None);
+        res
     }
 
-    pub fn on_test_passed(&mut self, test_name: &str) -> Result<(), Error> {
-        let suite_name = self.active_suite.as_ref().ok_or(Error::NoSuiteActive)?;
+    /// Tear down the tests set up deeper than `depth` in the suite stack that never
+    /// were, e.g. because the test crashed or the listener missed its end, recording
+    /// those without a result as aborted
+    fn abort_open_tests(&mut self, depth: usize) -> Result<(), Error> {
+        let mut aborted: Vec<TestKey> = self
+            .tests_to_timelines
+            .iter()
+            .filter(|(_, t)| t.suite_depth > depth)
+            .map(|(key, _)| key.clone())
+            .collect();
+        aborted.sort_unstable();
 
-        if let Some(timeline_id) = self.tests_to_timelines.get(test_name) {
-            self.rt.block_on(self.client.open_timeline(*timeline_id))?;
+        for key in aborted {
+            let test = self.tests_to_timelines.remove(&key).expect("open test");
+            warn!(test_key = key, "Aborting a test that was never torn down");
+            self.abort_test(&key, test)?;
+        }
+        Ok(())
+    }
+
+    /// Tear down `test`, already taken out of the open tests, recording it as aborted
+    /// unless it already has a result
+    fn abort_test(&mut self, key: &str, test: OpenTest) -> Result<(), Error> {
+        if self.active_test.as_ref().map(|t| t.key.as_str()) == Some(key) {
+            self.active_test = None;
+            self.keyword_stack.clear();
+        }
+        self.open_timeline(test.timeline_id)?;
+        if !test.has_result {
             event(
                 self,
                 [
                     ("event.name", "test_result".into()),
-                    ("event.suite.name", suite_name.into()),
-                    ("event.test.name", test_name.into()),
-                    ("event.test.result", "passed".into()),
-                    ("event.test.result.code", 0_i64.into()),
+                    ("event.suite.name", test.suite_name.as_str().into()),
+                    ("event.test.name", test.name.as_str().into()),
+                    ("event.test.result", "aborted".into()),
+                    ("event.test.result.code", ABORTED_RESULT_CODE.into()),
                 ],
             )?;
         }
+        event(
+            self,
+            [
+                ("event.name", "test_teardown".into()),
+                ("event.suite.name", test.suite_name.into()),
+                ("event.test.name", test.name.into()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn on_test_setup(
+        &mut self,
+        test_name: &str,
+        tags: Option<Vec<String>>,
+        documentation: Option<&str>,
+        source: Option<&str>,
+        lineno: Option<i64>,
+        timeout: Option<&str>,
+        long_name: Option<&str>,
+        attempt: Option<u32>,
+        original_timeline_id: Option<&str>,
+    ) -> Result<(), Error> {
+        let original_timeline_id = original_timeline_id.map(parse_timeline_id).transpose()?;
+        let key = match long_name {
+            Some(long_name) => long_name.to_owned(),
+            None => self.qualified_test_key(test_name)?,
+        };
+        let suite = self.suite_stack.last().ok_or(Error::NoSuiteActive)?;
+        let suite_name = suite.name.clone();
+        let suite_long_name = suite.long_name.clone();
+        let suite_timeline_id = suite.timeline_id;
+
+        let timeline_id = TimelineId::allocate();
+        // The test it replaces never gets torn down, so finish it off here
+        let duplicate_of = match self.tests_to_timelines.remove(&key) {
+            Some(previous) => {
+                let previous_timeline_id = previous.timeline_id;
+                warn!(
+                    test_key = key,
+                    previous = %previous_timeline_id,
+                    "Test set up while a test with the same name is still open"
+                );
+                self.abort_test(&key, previous)?;
+                Some(previous_timeline_id)
+            }
+            None => None,
+        };
+        self.tests_to_timelines.insert(
+            key.clone(),
+            OpenTest {
+                name: test_name.to_owned(),
+                suite_name: suite_name.clone(),
+                suite_depth: self.suite_stack.len(),
+                timeline_id,
+                has_result: false,
+            },
+        );
+        let previous_attempt = self.test_attempts.get(&key).copied();
+        let attempt = attempt.unwrap_or_else(|| previous_attempt.map_or(1, |(n, _)| n + 1));
+        self.test_attempts
+            .insert(key.clone(), (attempt, timeline_id));
+        self.open_timeline(timeline_id)?;
+
+        let mut attrs: Vec<(String, AttrVal)> = vec![
+            ("timeline.name".into(), "robot_framework".into()),
+            (
+                "timeline.robot_framework.suite.name".into(),
+                suite_name.as_str().into(),
+            ),
+            (
+                "timeline.robot_framework.suite.path".into(),
+                suite_long_name.into(),
+            ),
+            (
+                "timeline.robot_framework.suite.timeline_id".into(),
+                suite_timeline_id.into(),
+            ),
+            (
+                "timeline.robot_framework.test.name".into(),
+                test_name.into(),
+            ),
+            (
+                "timeline.robot_framework.test.long_name".into(),
+                key.as_str().into(),
+            ),
+            (
+                "timeline.robot_framework.test.attempt".into(),
+                attempt.into(),
+            ),
+            ("timeline.id".into(), timeline_id.into()),
+            ("timeline.clock_style".into(), "utc".into()),
+            ("timeline.run_id".into(), self.run_id.as_str().into()),
+        ];
+        if let Some((_, previous_timeline_id)) = previous_attempt {
+            attrs.push((
+                "timeline.robot_framework.test.previous_attempt.timeline_id".into(),
+                previous_timeline_id.into(),
+            ));
+        }
+        if let Some(original_timeline_id) = original_timeline_id {
+            attrs.push((
+                "timeline.robot_framework.test.original_timeline_id".into(),
+                original_timeline_id.into(),
+            ));
+        }
+        if let Some(tags) = tags {
+            attrs.push((
+                "timeline.robot_framework.test.tags".into(),
+                tags.join(",").into(),
+            ));
+            for tag in tags.iter() {
+                attrs.push((
+                    format!(
+                        "timeline.robot_framework.test.tag.{}",
+                        attr_key_segment(tag)
+                    ),
+                    true.into(),
+                ));
+            }
+        }
+        if let Some(documentation) = documentation {
+            attrs.push((
+                "timeline.robot_framework.test.documentation".into(),
+                truncate(documentation, self.max_message_len).into(),
+            ));
+        }
+        if let Some(source) = source {
+            attrs.push(("timeline.robot_framework.test.source".into(), source.into()));
+        }
+        if let Some(lineno) = lineno {
+            attrs.push(("timeline.robot_framework.test.lineno".into(), lineno.into()));
+        }
+        if let Some(timeout) = timeout {
+            attrs.push((
+                "timeline.robot_framework.test.timeout".into(),
+                timeout.into(),
+            ));
+        }
+        attrs.extend(self.inherited_timeline_attrs());
+        timeline_metadata(self, attrs)?;
+
+        let mut attrs: Vec<(&str, AttrVal)> = vec![
+            ("event.name", "test_setup".into()),
+            ("event.suite.name", suite_name.into()),
+            ("event.test.name", test_name.into()),
+            ("event.test.attempt", attempt.into()),
+        ];
+        if let Some(previous) = duplicate_of {
+            attrs.push(("event.test.duplicate_of", previous.into()));
+        }
+        event(self, attrs)?;
+        self.active_test = Some(ActiveTest {
+            name: test_name.to_owned(),
+            key,
+        });
+        self.keyword_stack.clear();
+
         Ok(())
     }
 
-    pub fn on_test_failed(&mut self, test_name: &str) -> Result<(), Error> {
-        let suite_name = self.active_suite.as_ref().ok_or(Error::NoSuiteActive)?;
+    fn on_test_teardown(&mut self, test_name: &str) -> Result<(), Error> {
+        let suite_name = self.active_suite_name()?;
+        let key = self.test_key(test_name)?;
 
-        if let Some(timeline_id) = self.tests_to_timelines.get(test_name) {
-            self.rt.block_on(self.client.open_timeline(*timeline_id))?;
+        if self.active_test.as_ref().map(|t| &t.key) == Some(&key) {
+            self.active_test = None;
+            self.keyword_stack.clear();
+        }
+
+        if let Some(test) = self.tests_to_timelines.remove(&key) {
+            self.open_timeline(test.timeline_id)?;
             event(
                 self,
                 [
-                    ("event.name", "test_result".into()),
+                    ("event.name", "test_teardown".into()),
                     ("event.suite.name", suite_name.into()),
                     ("event.test.name", test_name.into()),
-                    ("event.test.result", "failed".into()),
-                    ("event.test.result.code", 1_i64.into()),
                 ],
             )?;
         }
         Ok(())
     }
 
-    pub fn start_component(&mut self, component_name: &str) -> Result<u32, Error> {
-        let nonce = self.global_nonce;
-        self.global_nonce += 1;
-        event(
+    fn on_test_passed(&mut self, test_name: &str) -> Result<(), Error> {
+        self.test_result(test_name, "passed", PASSED_RESULT_CODE, [])
+    }
+
+    fn on_test_failed(
+        &mut self,
+        test_name: &str,
+        message: Option<&str>,
+        error_type: Option<&str>,
+        traceback: Option<&str>,
+    ) -> Result<(), Error> {
+        let mut attrs: Vec<(&str, AttrVal)> = Vec::new();
+        if let Some(message) = message {
+            attrs.push((
+                "event.test.result.message",
+                truncate(message, self.max_message_len).into(),
+            ));
+        }
+        if let Some(error_type) = error_type {
+            attrs.push(("event.test.result.error_type", error_type.into()));
+        }
+        if let Some(traceback) = traceback {
+            attrs.push((
+                "event.test.result.traceback",
+                truncate(traceback, self.max_message_len).into(),
+            ));
+        }
+        self.test_result(test_name, "failed", FAILED_RESULT_CODE, attrs)
+    }
+
+    fn on_test_skipped(&mut self, test_name: &str, reason: Option<&str>) -> Result<(), Error> {
+        self.test_result_with_reason(test_name, "skipped", SKIPPED_RESULT_CODE, reason)
+    }
+
+    fn on_test_not_run(&mut self, test_name: &str, reason: Option<&str>) -> Result<(), Error> {
+        self.test_result_with_reason(test_name, "not_run", NOT_RUN_RESULT_CODE, reason)
+    }
+
+    fn start_keyword(
+        &mut self,
+        keyword_name: &str,
+        library: Option<&str>,
+        args: Option<Vec<String>>,
+    ) -> Result<(), Error> {
+        let suite_name = self.active_suite_name()?;
+        let test_name = self.active_test.as_ref().map(|t| t.name.clone());
+        let Some(timeline_id) = self.current_timeline() else {
+            return Ok(());
+        };
+        self.open_timeline(timeline_id)?;
+
+        let args = args.unwrap_or_default();
+        let mut attrs: Vec<(String, AttrVal)> = vec![
+            ("event.name".into(), "keyword_start".into()),
+            ("event.suite.name".into(), suite_name.into()),
+            ("event.keyword.name".into(), keyword_name.into()),
+            (
+                "event.keyword.depth".into(),
+                (self.keyword_stack.len() as i64).into(),
+            ),
+            (
+                "event.keyword.args".into(),
+                truncate(&args.join(", "), self.max_message_len).into(),
+            ),
+        ];
+        if let Some(test_name) = test_name {
+            attrs.push(("event.test.name".into(), test_name.into()));
+        }
+        if let Some(library) = library {
+            attrs.push(("event.keyword.library".into(), library.into()));
+        }
+        for (idx, arg) in args.iter().enumerate() {
+            attrs.push((
+                format!("event.keyword.arg.{idx}"),
+                truncate(arg, self.max_message_len).into(),
+            ));
+        }
+
+        event(self, attrs)?;
+        self.keyword_stack.push(OpenKeyword {
+            name: keyword_name.to_owned(),
+            started: Instant::now(),
+        });
+        Ok(())
+    }
+
+    fn end_keyword(
+        &mut self,
+        keyword_name: &str,
+        library: Option<&str>,
+        status: Option<&str>,
+    ) -> Result<(), Error> {
+        let suite_name = self.active_suite_name()?;
+        let test_name = self.active_test.as_ref().map(|t| t.name.clone());
+        let Some(timeline_id) = self.current_timeline() else {
+            return Ok(());
+        };
+        self.open_timeline(timeline_id)?;
+
+        // Matched by name, so a start that was never recorded doesn't shift every later
+        // keyword onto the wrong start, and ends that were missed are dropped
+        let start = match self
+            .keyword_stack
+            .iter()
+            .rposition(|k| k.name == keyword_name)
+        {
+            Some(idx) => self.keyword_stack.drain(idx..).next().map(|k| k.started),
+            None => None,
+        };
+        let mut attrs: Vec<(String, AttrVal)> = vec![
+            ("event.name".into(), "keyword_end".into()),
+            ("event.suite.name".into(), suite_name.into()),
+            ("event.keyword.name".into(), keyword_name.into()),
+            (
+                "event.keyword.depth".into(),
+                (self.keyword_stack.len() as i64).into(),
+            ),
+        ];
+        if let Some(test_name) = test_name {
+            attrs.push(("event.test.name".into(), test_name.into()));
+        }
+        if let Some(library) = library {
+            attrs.push(("event.keyword.library".into(), library.into()));
+        }
+        if let Some(status) = status {
+            attrs.push(("event.keyword.status".into(), status.into()));
+        }
+        if let Some(start) = start {
+            attrs.push((
+                "event.keyword.duration".into(),
+                (start.elapsed().as_nanos() as u64).into(),
+            ));
+        }
+
+        event(self, attrs)?;
+        Ok(())
+    }
+
+    fn start_component(&mut self, component_name: &str) -> Result<u32, Error> {
+        let timeline_id = self.current_timeline().ok_or(Error::NoSuiteActive)?;
+        self.open_timeline(timeline_id)?;
+
+        let nonce = event(
             self,
             [
                 ("event.name", "start_component".into()),
-                ("event.nonce", nonce.into()),
+                ("event.run_id", self.run_id.as_str().into()),
                 ("event.component_name", component_name.into()),
             ],
         )?;
+        self.components.insert(
+            nonce,
+            Component {
+                name: component_name.to_owned(),
+                suite_depth: self.suite_stack.len(),
+            },
+        );
         Ok(nonce)
     }
-}
 
-fn event<'a>(
-    c: &mut ModalityClient,
-    attrs: impl IntoIterator<Item = (&'a str, AttrVal)>,
-) -> Result<(), Error> {
-    let mut iattrs = HashMap::new();
-    for kv in attrs.into_iter() {
-        iattrs.insert(
-            c.rt.block_on(declare_attr_key(kv.0, &mut c.client, &mut c.attrs))?,
-            kv.1,
+    fn end_component(&mut self, nonce: u32, status: Option<&str>) -> Result<(), Error> {
+        let component_name = self
+            .components
+            .get(&nonce)
+            .ok_or(Error::UnknownComponent(nonce))?
+            .name
+            .clone();
+        let timeline_id = self.current_timeline().ok_or(Error::NoSuiteActive)?;
+        self.open_timeline(timeline_id)?;
+
+        let mut attrs: Vec<(&str, AttrVal)> = vec![
+            ("event.name", "end_component".into()),
+            ("event.run_id", self.run_id.as_str().into()),
+            ("event.component_nonce", nonce.into()),
+            ("event.component_name", component_name.into()),
+        ];
+        if let Some(status) = status {
+            attrs.push(("event.component_status", status.into()));
+        }
+        event(self, attrs)?;
+        // Only once the event is queued, so a failed call can be retried
+        self.components.remove(&nonce);
+        Ok(())
+    }
+
+    fn component_state(&mut self, nonce: u32, state: &str) -> Result<(), Error> {
+        let component_name = self
+            .components
+            .get(&nonce)
+            .ok_or(Error::UnknownComponent(nonce))?
+            .name
+            .clone();
+        let timeline_id = self.current_timeline().ok_or(Error::NoSuiteActive)?;
+        self.open_timeline(timeline_id)?;
+
+        event(
+            self,
+            [
+                ("event.name", "component_state".into()),
+                ("event.run_id", self.run_id.as_str().into()),
+                ("event.component_nonce", nonce.into()),
+                ("event.component_name", component_name.into()),
+                ("event.component_state", state.into()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn record_interaction(
+        &mut self,
+        remote_timeline_id: TimelineId,
+        remote_nonce: u64,
+        name: &str,
+        mut attrs: Vec<(String, AttrVal)>,
+    ) -> Result<(), Error> {
+        attrs.push((
+            "event.interaction.remote_timeline_id".into(),
+            remote_timeline_id.into(),
+        ));
+        attrs.push(("event.interaction.remote_nonce".into(), remote_nonce.into()));
+        self.user_event(name, attrs)
+    }
+
+    fn last_event_nonce(&self) -> Option<(String, u32)> {
+        self.last_event
+            .map(|e| (e.timeline_id.to_string(), e.nonce))
+    }
+
+    fn last_event_coordinate(&self) -> Option<(String, u128)> {
+        self.last_event
+            .map(|e| (e.timeline_id.to_string(), e.ordering))
+    }
+
+    fn current_timeline_id(&self) -> Option<String> {
+        self.current_timeline().map(|id| id.to_string())
+    }
+
+    fn suite_timeline_id(&self) -> Option<String> {
+        self.suite_stack.last().map(|s| s.timeline_id.to_string())
+    }
+
+    fn timeline_id_for(&self, test_name: &str) -> Option<String> {
+        let key = self.test_key(test_name).ok()?;
+        self.tests_to_timelines
+            .get(&key)
+            .map(|t| t.timeline_id.to_string())
+    }
+
+    fn set_timeline_attrs(
+        &mut self,
+        attrs: Vec<(String, AttrVal)>,
+        scope: TimelineAttrScope,
+    ) -> Result<(), Error> {
+        debug!(?scope, "set_timeline_attrs");
+
+        let active_test_timeline = self
+            .active_test
+            .as_ref()
+            .and_then(|t| self.tests_to_timelines.get(&t.key))
+            .map(|t| t.timeline_id);
+        let timelines: Vec<TimelineId> = match scope {
+            TimelineAttrScope::Test => vec![active_test_timeline.ok_or(Error::NoTestActive)?],
+            TimelineAttrScope::Suite => {
+                let suite = self.suite_stack.last_mut().ok_or(Error::NoSuiteActive)?;
+                suite.timeline_attrs.extend(attrs.iter().cloned());
+                std::iter::once(suite.timeline_id)
+                    .chain(active_test_timeline)
+                    .collect()
+            }
+            TimelineAttrScope::Run => {
+                for (k, v) in attrs.iter() {
+                    let k = k.strip_prefix("timeline.").unwrap_or(k);
+                    self.extra_timeline_attrs.insert(k.into(), v.clone());
+                }
+                self.suite_stack
+                    .iter()
+                    .map(|s| s.timeline_id)
+                    .chain(self.tests_to_timelines.values().map(|t| t.timeline_id))
+                    .collect()
+            }
+        };
+
+        for timeline_id in timelines {
+            self.open_timeline(timeline_id)?;
+            timeline_metadata(self, attrs.iter().cloned())?;
+        }
+        Ok(())
+    }
+
+    fn error_context(&self) -> ErrorContext {
+        ErrorContext {
+            suite_name: self.suite_stack.last().map(|s| s.name.clone()),
+            test_name: self.active_test.as_ref().map(|t| t.name.clone()),
+        }
+    }
+
+    /// In fail-open mode, log and count an ingest error and carry on as if the call
+    /// succeeded
+    fn fail_open<T: Default>(&mut self, res: Result<T, Error>) -> Result<T, Error> {
+        match res {
+            Err(e) if self.fail_open && e.is_ingest_error() => {
+                error!(error = %e, "Ingest error, continuing in fail-open mode");
+                self.ingest_errors += 1 + self.ingest.take_dropped_errors();
+                self.last_ingest_error = Some(e.to_string());
+                Ok(T::default())
+            }
+            res => res,
+        }
+    }
+
+    /// Summarize the ingest errors absorbed in fail-open mode since the last summary
+    fn report_ingest_errors(&mut self, suite_path: &str) {
+        let new_errors = self.ingest_errors - self.reported_ingest_errors;
+        if new_errors == 0 {
+            return;
+        }
+        self.reported_ingest_errors = self.ingest_errors;
+        warn!(
+            suite = suite_path,
+            errors = new_errors,
+            total_errors = self.ingest_errors,
+            last_error = self.last_ingest_error.as_deref().unwrap_or_default(),
+            "Ingest errors were ignored during the suite, its telemetry may be incomplete"
         );
     }
-    iattrs.insert(
-        c.rt.block_on(declare_attr_key(
-            "event.timestamp",
-            &mut c.client,
-            &mut c.attrs,
-        ))?,
+
+    /// Timeline attrs every new timeline gets: the run-wide ones, then those of each
+    /// suite on the stack from the outermost in, so inner suites take precedence
+    fn inherited_timeline_attrs(&self) -> Vec<(String, AttrVal)> {
+        self.extra_timeline_attrs
+            .iter()
+            .map(|(k, v)| (format!("timeline.{}", k), v.clone()))
+            .chain(
+                self.suite_stack
+                    .iter()
+                    .flat_map(|s| s.timeline_attrs.iter().cloned()),
+            )
+            .collect()
+    }
+
+    /// Resolve `test_name` to the key of its timeline: the active test's key when it
+    /// names the active test, `test_name` itself when it's already qualified by the
+    /// innermost suite's path, and otherwise `test_name` under that path
+    fn test_key(&self, test_name: &str) -> Result<TestKey, Error> {
+        if let Some(test) = self.active_test.as_ref() {
+            if test.name == test_name || test.key == test_name {
+                return Ok(test.key.clone());
+            }
+        }
+        self.qualified_test_key(test_name)
+    }
+
+    fn qualified_test_key(&self, test_name: &str) -> Result<TestKey, Error> {
+        let suite = self.suite_stack.last().ok_or(Error::NoSuiteActive)?;
+        match test_name.strip_prefix(suite.long_name.as_str()) {
+            Some(rest) if rest.starts_with('.') => Ok(test_name.to_owned()),
+            _ => Ok(format!("{}.{}", suite.long_name, test_name)),
+        }
+    }
+
+    fn active_suite_name(&self) -> Result<SuiteName, Error> {
+        Ok(self
+            .suite_stack
+            .last()
+            .ok_or(Error::NoSuiteActive)?
+            .name
+            .clone())
+    }
+
+    fn open_timeline(&mut self, timeline_id: TimelineId) -> Result<(), Error> {
+        self.ingest.open_timeline(timeline_id)?;
+        self.bound_timeline = Some(timeline_id);
+        Ok(())
+    }
+
+    fn close_timeline(&mut self) -> Result<(), Error> {
+        self.ingest.close_timeline()?;
+        self.bound_timeline = None;
+        Ok(())
+    }
+
+    /// Emit a user-defined event on the current timeline
+    fn user_event(&mut self, name: &str, user_attrs: Vec<(String, AttrVal)>) -> Result<(), Error> {
+        let suite_name = self.active_suite_name()?;
+        let timeline_id = self.current_timeline().ok_or(Error::NoSuiteActive)?;
+
+        let mut attrs: Vec<(String, AttrVal)> = vec![
+            ("event.name".into(), name.into()),
+            ("event.suite.name".into(), suite_name.into()),
+        ];
+        if let Some(test) = self.active_test.as_ref() {
+            attrs.push(("event.test.name".into(), test.name.as_str().into()));
+        }
+        attrs.extend(user_attrs);
+
+        self.open_timeline(timeline_id)?;
+        event(self, attrs)?;
+        Ok(())
+    }
+
+    fn test_result<'a>(
+        &mut self,
+        test_name: &str,
+        result: &str,
+        code: i64,
+        extra_attrs: impl IntoIterator<Item = (&'a str, AttrVal)>,
+    ) -> Result<(), Error> {
+        let suite_name = self.active_suite_name()?;
+        let key = self.test_key(test_name)?;
+
+        if let Some(timeline_id) = self.tests_to_timelines.get(&key).map(|t| t.timeline_id) {
+            self.open_timeline(timeline_id)?;
+            let mut attrs: Vec<(&str, AttrVal)> = vec![
+                ("event.name", "test_result".into()),
+                ("event.suite.name", suite_name.into()),
+                ("event.test.name", test_name.into()),
+                ("event.test.result", result.into()),
+                ("event.test.result.code", code.into()),
+            ];
+            attrs.extend(extra_attrs);
+            event(self, attrs)?;
+            if let Some(test) = self.tests_to_timelines.get_mut(&key) {
+                test.has_result = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// A `test_result` event with the (truncated) reason the test didn't pass or fail
+    fn test_result_with_reason(
+        &mut self,
+        test_name: &str,
+        result: &str,
+        code: i64,
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        let attrs = reason.map(|r| {
+            (
+                "event.test.result.reason",
+                truncate(r, self.max_message_len).into(),
+            )
+        });
+        self.test_result(test_name, result, code, attrs)
+    }
+
+    /// The timeline that keyword, component and user events belong to: the active
+    /// test's, otherwise the innermost suite's so suite setup and teardown keywords
+    /// are captured too
+    fn current_timeline(&self) -> Option<TimelineId> {
+        match self.active_test.as_ref() {
+            Some(test) => self
+                .tests_to_timelines
+                .get(&test.key)
+                .map(|t| t.timeline_id),
+            None => self.suite_stack.last().map(|s| s.timeline_id),
+        }
+    }
+}
+
+/// Whether the environment variable is set to something truthy like `1` or `yes`
+fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| parse_flag(&v))
+        .unwrap_or(false)
+}
+
+/// Parse a flag like `1`, `yes`, `off` or `false`
+fn parse_flag(s: &str) -> Option<bool> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_timeline_id(s: &str) -> Result<TimelineId, Error> {
+    Uuid::parse_str(s.trim())
+        .map(TimelineId::from)
+        .map_err(|_| Error::InvalidTimelineId(s.to_owned()))
+}
+
+/// Normalize free-form text such as a tag into a single attr key segment,
+/// lower-cased like Robot's own tag matching, e.g. `Smoke Test` -> `smoke_test`
+fn attr_key_segment(s: &str) -> String {
+    s.trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Truncate `s` to at most `max_len` bytes, on a char boundary
+fn truncate(s: &str, max_len: usize) -> &str {
+    if s.len() <= max_len {
+        return s;
+    }
+    let mut end = max_len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+fn timeline_metadata<K: AsRef<str>>(
+    c: &mut ClientState,
+    attrs: impl IntoIterator<Item = (K, AttrVal)>,
+) -> Result<(), Error> {
+    let attrs = attrs
+        .into_iter()
+        .map(|(k, v)| (k.as_ref().to_owned(), v))
+        .collect();
+    c.ingest.timeline_metadata(attrs)
+}
+
+/// Queue an event on the bound timeline, returning the nonce assigned to it.
+/// The timestamp is captured here rather than when the worker sends it.
+fn event<K: AsRef<str>>(
+    c: &mut ClientState,
+    attrs: impl IntoIterator<Item = (K, AttrVal)>,
+) -> Result<u32, Error> {
+    let mut attrs: Vec<(String, AttrVal)> = attrs
+        .into_iter()
+        .map(|(k, v)| (k.as_ref().to_owned(), v))
+        .collect();
+    attrs.push((
+        "event.timestamp".into(),
         Nanoseconds::from(
             SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)
@@ -280,23 +1855,35 @@ fn event<'a>(
                 .as_nanos() as u64,
         )
         .into(),
-    );
+    ));
+
+    let nonce = c.global_nonce;
+    c.global_nonce += 1;
+    attrs.push(("event.nonce".into(), nonce.into()));
 
-    c.rt.block_on(c.client.event(c.ordering, iattrs))?;
+    c.ingest.event(c.ordering, attrs)?;
+    c.last_event = c.bound_timeline.map(|timeline_id| EmittedEvent {
+        timeline_id,
+        ordering: c.ordering,
+        nonce,
+    });
     c.ordering += 1;
-    Ok(())
+    Ok(nonce)
 }
 
-async fn declare_attr_key(
-    k: &str,
-    client: &mut DynamicIngestClient,
-    attrs: &mut HashMap<String, InternedAttrKey>,
-) -> Result<InternedAttrKey, Error> {
-    if let Some(ikey) = attrs.get(k) {
-        Ok(*ikey)
-    } else {
-        let ikey = client.declare_attr_key(k.to_owned()).await?;
-        attrs.insert(k.to_owned(), ikey);
-        Ok(ikey)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_on_a_char_boundary() {
+        assert_eq!(truncate("hello", 10), "hello");
+        assert_eq!(truncate("hello", 5), "hello");
+        assert_eq!(truncate("hello", 3), "hel");
+        // 'é' is two bytes, so cutting into it backs off to before it
+        assert_eq!(truncate("café", 4), "caf");
+        assert_eq!(truncate("café", 5), "café");
+        assert_eq!(truncate("日本", 2), "");
+        assert_eq!(truncate("日本", 3), "日");
     }
 }
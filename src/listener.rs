@@ -0,0 +1,299 @@
+use crate::error::Error;
+use crate::{parse_flag, ClientOptions, ModalityClient, DEFAULT_CLOSE_TIMEOUT_SECS};
+use pyo3::prelude::*;
+use tracing::debug;
+
+/// A Robot Framework listener (API version 3) that drives a [`ModalityClient`]
+/// from the suite and test lifecycle, e.g.
+/// `robot --listener modality_client.ModalityListener tests/`.
+///
+/// Listener arguments are forwarded to the client as additional timeline attributes,
+/// e.g. `--listener modality_client.ModalityListener:rig=bench_2`, except for
+/// `ingest_url=`, `auth_token=`, `allow_insecure_tls=`, `timeout_secs=`, `run_id=`,
+/// `previous_run_id=`, `fail_open=` and `handle_signals=`, which set the client's
+/// options of the same name. Separate the arguments with `;` when one contains
+/// a colon, e.g.
+/// `--listener "modality_client.ModalityListener;ingest_url=modality-ingest://rig:14182"`.
+#[pyclass]
+pub struct ModalityListener {
+    client: ModalityClient,
+}
+
+#[pymethods]
+impl ModalityListener {
+    #[classattr]
+    const ROBOT_LISTENER_API_VERSION: u32 = 3;
+
+    #[new]
+    #[pyo3(signature = (*args))]
+    pub fn new(py: Python<'_>, args: Vec<String>) -> PyResult<Self> {
+        let client = ModalityClient::with_options(py, listener_options(args)?)?;
+        Ok(Self { client })
+    }
+
+    pub fn start_suite(
+        &self,
+        py: Python<'_>,
+        data: &Bound<'_, PyAny>,
+        _result: &Bound<'_, PyAny>,
+    ) -> PyResult<()> {
+        let suite_name: String = data.getattr("name")?.extract()?;
+        let long_name = long_name(data)?;
+        let suite_id: String = data.getattr("id")?.extract()?;
+        let source = optional_str(&data.getattr("source")?)?;
+        let documentation: String = data.getattr("doc")?.extract()?;
+        self.client.on_suite_setup(
+            py,
+            &suite_name,
+            Some(&long_name),
+            Some(&suite_id),
+            source.as_deref(),
+            Some(documentation.as_str()).filter(|d| !d.is_empty()),
+        )?;
+        Ok(())
+    }
+
+    pub fn end_suite(
+        &self,
+        py: Python<'_>,
+        _data: &Bound<'_, PyAny>,
+        result: &Bound<'_, PyAny>,
+    ) -> PyResult<()> {
+        // Tear down even when the result can't be recorded, or the suite would stay
+        // on the stack with every later suite nested under it
+        let res = self.suite_result(py, result);
+        let teardown = self.client.on_suite_teardown(py);
+        res.and(teardown)
+    }
+
+    pub fn start_test(
+        &self,
+        py: Python<'_>,
+        data: &Bound<'_, PyAny>,
+        _result: &Bound<'_, PyAny>,
+    ) -> PyResult<()> {
+        let test_name: String = data.getattr("name")?.extract()?;
+        let tags = data
+            .getattr("tags")?
+            .iter()?
+            .map(|tag| tag?.extract())
+            .collect::<PyResult<Vec<String>>>()?;
+        let documentation: String = data.getattr("doc")?.extract()?;
+        let source = optional_str(&data.getattr("source")?)?;
+        let lineno: Option<i64> = data.getattr("lineno")?.extract()?;
+        let timeout = optional_str(&data.getattr("timeout")?)?;
+        let long_name = long_name(data)?;
+        self.client.on_test_setup(
+            py,
+            &test_name,
+            Some(tags),
+            Some(documentation.as_str()).filter(|d| !d.is_empty()),
+            source.as_deref(),
+            lineno,
+            timeout.as_deref(),
+            Some(&long_name),
+            None,
+            None,
+        )?;
+        Ok(())
+    }
+
+    pub fn end_test(
+        &self,
+        py: Python<'_>,
+        data: &Bound<'_, PyAny>,
+        result: &Bound<'_, PyAny>,
+    ) -> PyResult<()> {
+        let test_name: String = data.getattr("name")?.extract()?;
+        let res = self.test_result(py, &test_name, result);
+        let teardown = self.client.on_test_teardown(py, &test_name);
+        res.and(teardown)
+    }
+
+    pub fn start_keyword(
+        &self,
+        py: Python<'_>,
+        data: &Bound<'_, PyAny>,
+        result: &Bound<'_, PyAny>,
+    ) -> PyResult<()> {
+        let keyword_name: String = data.getattr("name")?.extract()?;
+        let library = keyword_library(result)?;
+        let args = data
+            .getattr("args")?
+            .iter()?
+            .map(|arg| arg?.str()?.extract())
+            .collect::<PyResult<Vec<String>>>()?;
+        self.client
+            .start_keyword(py, &keyword_name, library.as_deref(), Some(args))?;
+        Ok(())
+    }
+
+    pub fn end_keyword(
+        &self,
+        py: Python<'_>,
+        data: &Bound<'_, PyAny>,
+        result: &Bound<'_, PyAny>,
+    ) -> PyResult<()> {
+        let keyword_name: String = data.getattr("name")?.extract()?;
+        let library = keyword_library(result)?;
+        let status: String = result.getattr("status")?.extract()?;
+        self.client
+            .end_keyword(py, &keyword_name, library.as_deref(), Some(&status))?;
+        Ok(())
+    }
+
+    pub fn close(&self, py: Python<'_>) -> PyResult<()> {
+        self.client.close(py, DEFAULT_CLOSE_TIMEOUT_SECS)
+    }
+}
+
+impl ModalityListener {
+    fn suite_result(&self, py: Python<'_>, result: &Bound<'_, PyAny>) -> PyResult<()> {
+        let status: String = result.getattr("status")?.extract()?;
+        let message: String = result.getattr("message")?.extract()?;
+        self.client.on_suite_result(py, &status, Some(&message))
+    }
+
+    fn test_result(
+        &self,
+        py: Python<'_>,
+        test_name: &str,
+        result: &Bound<'_, PyAny>,
+    ) -> PyResult<()> {
+        let status: String = result.getattr("status")?.extract()?;
+        let message: String = result.getattr("message")?.extract()?;
+        let message = Some(message.as_str()).filter(|m| !m.is_empty());
+        match status.as_str() {
+            "PASS" => self.client.on_test_passed(py, test_name)?,
+            "FAIL" => self
+                .client
+                .on_test_failed(py, test_name, message, None, None)?,
+            "SKIP" => self.client.on_test_skipped(py, test_name, message)?,
+            "NOT RUN" => self.client.on_test_not_run(py, test_name, message)?,
+            _ => debug!(
+                test_name,
+                status, "Test finished with an unrecognized status"
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// Stringify an optional value such as a `pathlib.Path` source.
+fn optional_str(value: &Bound<'_, PyAny>) -> PyResult<Option<String>> {
+    if value.is_none() {
+        Ok(None)
+    } else {
+        Ok(Some(value.str()?.extract()?))
+    }
+}
+
+/// The fully qualified name, `full_name` on RF 7+ and `longname` before that.
+fn long_name(data: &Bound<'_, PyAny>) -> PyResult<String> {
+    let attr = if data.hasattr("full_name")? {
+        "full_name"
+    } else {
+        "longname"
+    };
+    data.getattr(attr)?.extract()
+}
+
+/// The keyword's owning library or resource name, `owner` on RF 7+ and `libname` before that.
+fn keyword_library(result: &Bound<'_, PyAny>) -> PyResult<Option<String>> {
+    let attr = if result.hasattr("owner")? {
+        "owner"
+    } else {
+        "libname"
+    };
+    result.getattr(attr)?.extract()
+}
+
+/// Split the listener arguments into client options and timeline attributes.
+fn listener_options(args: Vec<String>) -> Result<ClientOptions, Error> {
+    let mut options = ClientOptions::default();
+    for arg in args {
+        let invalid = |reason: &str| Error::InvalidListenerArg {
+            arg: arg.clone(),
+            reason: reason.to_owned(),
+        };
+        match arg.split_once('=') {
+            Some(("ingest_url", url)) => options.ingest_url = Some(url.to_owned()),
+            Some(("auth_token", token)) => options.auth_token = Some(token.to_owned()),
+            Some(("allow_insecure_tls", flag)) => {
+                let flag = parse_flag(flag).ok_or_else(|| invalid("expected true or false"))?;
+                options.allow_insecure_tls = Some(flag);
+            }
+            Some(("run_id", run_id)) => options.run_id = Some(run_id.to_owned()),
+            Some(("previous_run_id", run_id)) => options.previous_run_id = Some(run_id.to_owned()),
+            Some(("fail_open", flag)) => {
+                let flag = parse_flag(flag).ok_or_else(|| invalid("expected true or false"))?;
+                options.fail_open = Some(flag);
+            }
+            Some(("handle_signals", flag)) => {
+                let flag = parse_flag(flag).ok_or_else(|| invalid("expected true or false"))?;
+                options.handle_signals = Some(flag);
+            }
+            Some(("timeout_secs", secs)) => {
+                let secs = secs
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid("expected a number of seconds"))?;
+                options.timeout_secs = Some(secs);
+            }
+            _ => options.additional_timeline_attrs.push(arg),
+        }
+    }
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn client_options_are_split_from_timeline_attrs() {
+        let options = listener_options(args(&[
+            "rig=bench_2",
+            "ingest_url=modality-ingest://rig:14182",
+            "auth_token=00ff",
+            "allow_insecure_tls=yes",
+            "timeout_secs=2.5",
+            "run_id=nightly-42",
+            "previous_run_id=nightly-41",
+            "fail_open=1",
+            "handle_signals=off",
+        ]))
+        .unwrap();
+        assert_eq!(options.additional_timeline_attrs, ["rig=bench_2"]);
+        assert_eq!(
+            options.ingest_url.as_deref(),
+            Some("modality-ingest://rig:14182")
+        );
+        assert_eq!(options.auth_token.as_deref(), Some("00ff"));
+        assert_eq!(options.allow_insecure_tls, Some(true));
+        assert_eq!(options.timeout_secs, Some(2.5));
+        assert_eq!(options.run_id.as_deref(), Some("nightly-42"));
+        assert_eq!(options.previous_run_id.as_deref(), Some("nightly-41"));
+        assert_eq!(options.fail_open, Some(true));
+        assert_eq!(options.handle_signals, Some(false));
+    }
+
+    #[test]
+    fn invalid_client_options_are_rejected() {
+        for arg in [
+            "allow_insecure_tls=maybe",
+            "timeout_secs=soon",
+            "fail_open=sometimes",
+        ] {
+            let err = listener_options(args(&[arg])).err();
+            assert!(
+                matches!(&err, Some(Error::InvalidListenerArg { arg: a, .. }) if a == arg),
+                "{arg}: {err:?}"
+            );
+        }
+    }
+}
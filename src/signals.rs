@@ -0,0 +1,141 @@
+use crate::ingest::POLL_INTERVAL;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How long to spend flushing after a signal before letting it take its course
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+static INSTALL: Once = Once::new();
+/// Signals received but not yet recorded, counted from within the signal handler
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Start a thread that, on SIGINT or SIGTERM, records a `run_interrupted` event on
+/// every open timeline of every client and flushes.
+///
+/// Whatever handler is installed at this point, e.g. Python's or Robot's, still runs
+/// as usual. A signal that would have terminated the process with its default
+/// disposition still does, once the flush is done. Only the first call does anything.
+pub(crate) fn install() {
+    INSTALL.call_once(|| {
+        if let Err(e) = imp::spawn() {
+            warn!(error = %e, "Failed to install signal handlers");
+        }
+    });
+}
+
+/// Wait up to [`FLUSH_TIMEOUT`] for any signal that's been received to be recorded,
+/// so closing clients as the interpreter exits doesn't beat the interruption to it
+pub(crate) fn wait_for_pending() {
+    let deadline = Instant::now() + FLUSH_TIMEOUT;
+    while PENDING.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::{FLUSH_TIMEOUT, PENDING};
+    use std::io;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use tokio::runtime;
+    use tokio::signal::unix::{signal, Signal, SignalKind};
+    use tracing::debug;
+
+    struct Handled {
+        name: &'static str,
+        signum: libc::c_int,
+        signal: Signal,
+        /// Whether the signal had its default disposition before we registered for it,
+        /// which registering replaces
+        terminates: bool,
+    }
+
+    pub(super) fn spawn() -> io::Result<()> {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let mut handled = {
+            let _guard = rt.enter();
+            [
+                handle("SIGINT", libc::SIGINT, SignalKind::interrupt())?,
+                handle("SIGTERM", libc::SIGTERM, SignalKind::terminate())?,
+            ]
+        };
+
+        thread::Builder::new()
+            .name("modality-signals".to_owned())
+            .spawn(move || loop {
+                // Recording the interruption blocks on the ingest worker, so it has to
+                // happen outside the runtime
+                let idx = rt.block_on(async {
+                    let [sigint, sigterm] = &mut handled;
+                    tokio::select! {
+                        Some(()) = sigint.signal.recv() => Some(0),
+                        Some(()) = sigterm.signal.recv() => Some(1),
+                        else => None,
+                    }
+                });
+                let Some(idx) = idx else {
+                    break;
+                };
+                let sig = &handled[idx];
+                debug!(signal = sig.name, "Received signal");
+                crate::interrupt_clients(sig.name, FLUSH_TIMEOUT);
+                PENDING.store(0, Ordering::SeqCst);
+                if sig.terminates {
+                    terminate(sig.signum);
+                }
+            })?;
+        Ok(())
+    }
+
+    fn handle(name: &'static str, signum: libc::c_int, kind: SignalKind) -> io::Result<Handled> {
+        let terminates = has_default_disposition(signum);
+        // SAFETY: the action only touches an atomic, which is async-signal-safe
+        unsafe {
+            signal_hook_registry::register(signum, || {
+                PENDING.fetch_add(1, Ordering::SeqCst);
+            })?;
+        }
+        Ok(Handled {
+            name,
+            signum,
+            signal: signal(kind)?,
+            terminates,
+        })
+    }
+
+    fn has_default_disposition(signum: libc::c_int) -> bool {
+        // SAFETY: a null action only queries the current one into `current`
+        unsafe {
+            let mut current: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(signum, std::ptr::null(), &mut current) == 0
+                && current.sa_sigaction == libc::SIG_DFL
+        }
+    }
+
+    /// Restore the default disposition and re-raise, ending the process the way the
+    /// signal would have without us
+    fn terminate(signum: libc::c_int) -> ! {
+        // SAFETY: plain libc calls with a valid signal number
+        unsafe {
+            libc::signal(signum, libc::SIG_DFL);
+            libc::raise(signum);
+        }
+        std::process::exit(128 + signum)
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    pub(super) fn spawn() -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "signal handling is only supported on unix",
+        ))
+    }
+}
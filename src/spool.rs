@@ -0,0 +1,264 @@
+use crate::error::Error;
+use auxon_sdk::api::{AttrVal, TimelineId};
+use minicbor::{Decode, Encode};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// One ingest operation, as recorded in a spool file.
+///
+/// Events keep the ordering and `event.timestamp` they were given when recorded, so a
+/// replay produces the same timelines as a live upload would have.
+#[derive(Clone, Debug, PartialEq, Decode, Encode)]
+pub(crate) enum SpoolRecord {
+    #[n(0)]
+    OpenTimeline {
+        #[n(0)]
+        id: TimelineId,
+    },
+
+    #[n(1)]
+    TimelineMetadata {
+        #[n(0)]
+        attrs: Vec<(String, AttrVal)>,
+    },
+
+    #[n(2)]
+    Event {
+        #[n(0)]
+        be_ordering: Vec<u8>,
+
+        #[n(1)]
+        attrs: Vec<(String, AttrVal)>,
+    },
+}
+
+impl SpoolRecord {
+    pub(crate) fn event(ordering: u128, attrs: Vec<(String, AttrVal)>) -> Self {
+        SpoolRecord::Event {
+            be_ordering: ordering.to_be_bytes().to_vec(),
+            attrs,
+        }
+    }
+}
+
+/// The ordering of an event record, from its big-endian bytes
+pub(crate) fn event_ordering(be_ordering: &[u8]) -> u128 {
+    be_ordering
+        .iter()
+        .fold(0, |ordering, b| (ordering << 8) | u128::from(*b))
+}
+
+/// The largest record a spool file may hold, well past anything the client writes, so
+/// a corrupt length can't have the reader allocate gigabytes
+const MAX_RECORD_LEN: usize = 64 * 1024 * 1024;
+
+/// Appends records to a spool file, each one a big-endian `u32` length followed by
+/// the CBOR-encoded record, like the ingest protocol's framing
+pub(crate) struct SpoolWriter {
+    file: BufWriter<File>,
+}
+
+impl SpoolWriter {
+    pub(crate) fn open(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: BufWriter::new(file),
+        })
+    }
+
+    pub(crate) fn append(&mut self, record: &SpoolRecord) -> Result<(), Error> {
+        let buf = minicbor::to_vec(record)?;
+        if buf.len() > MAX_RECORD_LEN {
+            return Err(minicbor::encode::Error::Message("spool record too large").into());
+        }
+        self.file.write_all(&(buf.len() as u32).to_be_bytes())?;
+        self.file.write_all(&buf)?;
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<(), Error> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+/// Reads back the records of a spool file, in the order they were written
+pub(crate) struct SpoolReader {
+    file: BufReader<File>,
+    buf: Vec<u8>,
+}
+
+impl SpoolReader {
+    pub(crate) fn open(path: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            file: BufReader::new(File::open(path)?),
+            buf: Vec::new(),
+        })
+    }
+
+    /// The next record, or `None` at the end of the file. A record cut short by a
+    /// crash while it was being written is treated as the end of the file.
+    pub(crate) fn next_record(&mut self) -> Result<Option<SpoolRecord>, Error> {
+        let mut len = [0; 4];
+        if !self.read_exact_or_eof(&mut len)? {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_RECORD_LEN {
+            return Err(
+                minicbor::decode::Error::Message("spool record length out of range").into(),
+            );
+        }
+        self.buf.resize(len, 0);
+        let mut buf = std::mem::take(&mut self.buf);
+        let complete = self.read_exact_or_eof(&mut buf)?;
+        let record = if complete {
+            Some(minicbor::decode(&buf)?)
+        } else {
+            None
+        };
+        self.buf = buf;
+        Ok(record)
+    }
+
+    fn read_exact_or_eof(&mut self, buf: &mut [u8]) -> Result<bool, Error> {
+        match self.file.read_exact(buf) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A fresh path in the temp dir, removed again when dropped
+    pub(crate) struct TempPath(pub(crate) PathBuf);
+
+    impl TempPath {
+        pub(crate) fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "modality-spool-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = std::fs::remove_file(&path);
+            TempPath(path)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn records() -> Vec<SpoolRecord> {
+        vec![
+            SpoolRecord::OpenTimeline {
+                id: TimelineId::allocate(),
+            },
+            SpoolRecord::TimelineMetadata {
+                attrs: vec![("timeline.name".into(), "robot_framework".into())],
+            },
+            SpoolRecord::event(
+                42,
+                vec![
+                    ("event.name".into(), "test_setup".into()),
+                    ("event.test.attempt".into(), AttrVal::Integer(2)),
+                ],
+            ),
+        ]
+    }
+
+    fn write(path: &Path, records: &[SpoolRecord]) {
+        let mut writer = SpoolWriter::open(path).unwrap();
+        for record in records {
+            writer.append(record).unwrap();
+        }
+        writer.flush().unwrap();
+    }
+
+    fn read_all(path: &Path) -> Vec<SpoolRecord> {
+        let mut reader = SpoolReader::open(path).unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = reader.next_record().unwrap() {
+            records.push(record);
+        }
+        records
+    }
+
+    fn append_bytes(path: &Path, bytes: &[u8]) {
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = TempPath::new("round-trip");
+        let records = records();
+        write(&path.0, &records);
+        assert_eq!(read_all(&path.0), records);
+    }
+
+    #[test]
+    fn appends_to_an_existing_spool() {
+        let path = TempPath::new("append");
+        let records = records();
+        write(&path.0, &records[..1]);
+        write(&path.0, &records[1..]);
+        assert_eq!(read_all(&path.0), records);
+    }
+
+    #[test]
+    fn truncated_length_is_end_of_file() {
+        let path = TempPath::new("truncated-length");
+        let records = records();
+        write(&path.0, &records);
+        append_bytes(&path.0, &[0, 0]);
+        assert_eq!(read_all(&path.0), records);
+    }
+
+    #[test]
+    fn truncated_body_is_end_of_file() {
+        let path = TempPath::new("truncated-body");
+        let records = records();
+        write(&path.0, &records);
+        append_bytes(&path.0, &100u32.to_be_bytes());
+        append_bytes(&path.0, &[0; 10]);
+        assert_eq!(read_all(&path.0), records);
+    }
+
+    #[test]
+    fn oversized_length_is_a_decode_error() {
+        let path = TempPath::new("oversized");
+        write(&path.0, &[]);
+        append_bytes(&path.0, &u32::MAX.to_be_bytes());
+        let mut reader = SpoolReader::open(&path.0).unwrap();
+        assert!(matches!(reader.next_record(), Err(Error::SpoolDecode(_))));
+    }
+
+    #[test]
+    fn event_ordering_round_trip() {
+        let path = TempPath::new("ordering");
+        let orderings = [0, 1, 0x0102, u128::from(u64::MAX) + 1, u128::MAX];
+        let records: Vec<SpoolRecord> = orderings
+            .iter()
+            .map(|o| SpoolRecord::event(*o, Vec::new()))
+            .collect();
+        write(&path.0, &records);
+        let read: Vec<u128> = read_all(&path.0)
+            .iter()
+            .map(|r| match r {
+                SpoolRecord::Event { be_ordering, .. } => event_ordering(be_ordering),
+                r => panic!("unexpected record {r:?}"),
+            })
+            .collect();
+        assert_eq!(read, orderings);
+    }
+}